@@ -1,13 +1,121 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use pay_engine::{engine, transaction::utils::RandomTransactions};
+use pay_engine::client::ClientWallets;
+use pay_engine::engine::{self, Engine};
+use pay_engine::parser::Parser;
+use pay_engine::storage::{DenseTxStore, DenseWallets, TxStore, WalletStore};
+use pay_engine::transaction::{utils::RandomTransactions, DisputeWindow, TransactionLog};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
 use std::iter::Iterator;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Keeps track of how much memory the benchmarks use
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Set this variable to measure the storage on 100 million transactions
+/// rather than 1 million. It needs a few GiB of memory.
+const LARGE_RUN: &str = "PAY_ENGINE_BENCH_100M";
+
+/// Seed of the generated transactions, so every run replays the same stream
+const SEED: u64 = 42;
 
 fn exec_n_tx(n: usize) {
-    let tx_gen = RandomTransactions::new();
+    let tx_gen = RandomTransactions::with_seed(SEED);
     engine::run(&mut tx_gen.into_iter().take(n));
 }
 
+/// CSV of `n` random transactions
+fn csv_of_n_tx(n: usize) -> Vec<u8> {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for t in RandomTransactions::with_seed(SEED).take(n) {
+        csv.push_str(&format!("{}\n", t));
+    }
+    csv.into_bytes()
+}
+
+/// Run `n` transactions through an engine keeping its state in `wallets` and
+/// `tx_store`
+fn exec_n_tx_with<W: WalletStore, S: TxStore>(n: usize, wallets: W, tx_store: S) {
+    let mut engine = Engine::from_parts(
+        engine::Config::default(),
+        ClientWallets::with_store(wallets),
+        TransactionLog::with_store(tx_store, DisputeWindow::Unbounded),
+    );
+    for t in RandomTransactions::with_seed(SEED).take(n) {
+        let _ = engine.submit(&t);
+    }
+}
+
+/// Peak memory used, and time taken, by `f`
+fn measure(f: impl FnOnce()) -> (usize, Duration) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    (PEAK.load(Ordering::Relaxed) - before, start.elapsed())
+}
+
+/// A way to run a number of transactions, and its name
+type Run = (&'static str, fn(usize));
+
+/// Compare the memory and throughput of the dense stores with `HashMap`s
+fn storage_benchmark(c: &mut Criterion) {
+    let n = match std::env::var_os(LARGE_RUN) {
+        Some(_) => 100_000_000,
+        None => 1_000_000,
+    };
+    let runs: [Run; 2] = [
+        ("hash_map", |n| {
+            exec_n_tx_with(n, HashMap::new(), HashMap::new())
+        }),
+        ("dense", |n| {
+            exec_n_tx_with(n, DenseWallets::new(), DenseTxStore::new())
+        }),
+    ];
+    for (name, run) in runs.iter() {
+        let (peak, elapsed) = measure(|| run(n));
+        println!(
+            "storage/{}: {} transactions, peak memory {:.1} MiB, {:.0} tx/s",
+            name,
+            n,
+            peak as f64 / (1024.0 * 1024.0),
+            n as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    let mut group = c.benchmark_group("storage");
+    group.sample_size(10);
+    for (name, run) in runs.iter() {
+        group.bench_with_input(BenchmarkId::new(*name, 1_000_000), &1_000_000, |b, n| {
+            b.iter(|| run(*n))
+        });
+    }
+    group.finish();
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("tx_volume");
     group
@@ -19,7 +127,17 @@ fn criterion_benchmark(c: &mut Criterion) {
             b.iter(|| exec_n_tx(*n))
         });
     }
+    group.finish();
+
+    let mut group = c.benchmark_group("parsing");
+    for n in [10_000_usize, 1_000_000].iter() {
+        let csv = csv_of_n_tx(*n);
+        group.bench_with_input(BenchmarkId::new("csv_input", n), &csv, |b, csv| {
+            b.iter(|| Parser::from_reader(csv.as_slice()).count())
+        });
+    }
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, criterion_benchmark, storage_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,278 @@
+//! Fixed-point representation of money
+use crate::error::{Error, Result};
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Number of fractional digits an `Amount` holds
+pub const DECIMALS: u32 = 4;
+/// Number of units in `1.0`
+const SCALE: i64 = 10_i64.pow(DECIMALS);
+
+/// An exact amount of money, with four fractional digits.
+///
+/// Amounts are stored as an integer count of `0.0001` units, so adding and
+/// subtracting them never accumulates rounding errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// Create an amount from a number of `0.0001` units
+    pub const fn from_units(units: i64) -> Self {
+        Amount(units)
+    }
+
+    /// Get the number of `0.0001` units in this amount
+    pub const fn units(self) -> i64 {
+        self.0
+    }
+
+    /// Check if the amount is below zero
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Check if the amount is zero
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Add `rhs` to the amount, or `None` if the sum does not fit an `Amount`
+    pub fn checked_add(self, rhs: Amount) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    /// Subtract `rhs` from the amount, or `None` if the difference does not
+    /// fit an `Amount`
+    pub fn checked_sub(self, rhs: Amount) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+
+    /// Parse a decimal number from ASCII bytes, see `from_str()`.
+    ///
+    /// This is the fast path of the CSV parser, that never looks at rows as
+    /// text. Returns `None` if `s` is not a valid amount.
+    pub fn from_ascii(s: &[u8]) -> Option<Self> {
+        let (negative, digits) = match s.split_first() {
+            Some((b'-', rest)) => (true, rest),
+            Some((b'+', rest)) => (false, rest),
+            _ => (false, s),
+        };
+        let (int_part, frac_part) = match digits.iter().position(|b| *b == b'.') {
+            Some(dot) => (&digits[..dot], &digits[dot + 1..]),
+            None => (digits, &[][..]),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+        if frac_part.len() > DECIMALS as usize {
+            return None;
+        }
+        if !int_part
+            .iter()
+            .chain(frac_part.iter())
+            .all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let mut units: i64 = 0;
+        for b in int_part {
+            units = units.checked_mul(10)?.checked_add((b - b'0') as i64)?;
+        }
+        let mut frac: i64 = 0;
+        for b in frac_part {
+            frac = frac * 10 + (b - b'0') as i64;
+        }
+        frac *= 10_i64.pow(DECIMALS - frac_part.len() as u32);
+        let units = units.checked_mul(SCALE)?.checked_add(frac)?;
+
+        Some(Amount(if negative { -units } else { units }))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = Error;
+
+    /// Parse a decimal number such as `42`, `42.5` or `-0.0001`.
+    ///
+    /// Inputs with more than four fractional digits are rejected rather than
+    /// rounded.
+    fn from_str(s: &str) -> Result<Self> {
+        Amount::from_ascii(s.as_bytes()).ok_or_else(|| Error::InvalidAmount(s.to_string()))
+    }
+}
+
+impl fmt::Display for Amount {
+    /// Print the amount with as many fractional digits as needed, but at
+    /// least one (`2.0`, `1.5`, `98.5123`).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let int_part = abs / SCALE as u64;
+        let mut frac = abs % SCALE as u64;
+        let mut width = DECIMALS as usize;
+        while width > 1 && frac.is_multiple_of(10) {
+            frac /= 10;
+            width -= 1;
+        }
+        write!(f, "{}{}.{:0width$}", sign, int_part, frac, width = width)
+    }
+}
+
+/// The operators do not check for overflows: balances go through
+/// `checked_add()` and `checked_sub()`
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AmountVisitor {
+            empty_is_zero: false,
+        })
+    }
+}
+
+/// Deserialize an amount, reading an empty string as zero.
+///
+/// Inputs with an asset column leave the amount of disputes empty.
+pub(crate) fn deserialize_or_zero<'de, D>(deserializer: D) -> std::result::Result<Amount, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(AmountVisitor {
+        empty_is_zero: true,
+    })
+}
+
+struct AmountVisitor {
+    empty_is_zero: bool,
+}
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a decimal number with at most {} fractional digits",
+            DECIMALS
+        )
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Amount, E>
+    where
+        E: de::Error,
+    {
+        if v.is_empty() && self.empty_is_zero {
+            return Ok(Amount::ZERO);
+        }
+        v.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().expect("valid amount")
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(amt("42").units(), 420_000);
+        assert_eq!(amt("42.5").units(), 425_000);
+        assert_eq!(amt("0.0001").units(), 1);
+        assert_eq!(amt(".5").units(), 5_000);
+        assert_eq!(amt("3.").units(), 30_000);
+        assert_eq!(amt("-1.25").units(), -12_500);
+    }
+
+    #[test]
+    fn parse_too_precise() {
+        match "100.0123456789".parse::<Amount>() {
+            Err(Error::InvalidAmount(_)) => (),
+            otherwise => panic!("{:?}", otherwise),
+        }
+        assert!("0.00001".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn parse_garbage() {
+        for input in &["", ".", "-", "1.2.3", "1e5", "abc", "1,5", " 1"] {
+            assert!(input.parse::<Amount>().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(amt("2").to_string(), "2.0");
+        assert_eq!(amt("1.5").to_string(), "1.5");
+        assert_eq!(amt("98.5123").to_string(), "98.5123");
+        assert_eq!(amt("0.0100").to_string(), "0.01");
+        assert_eq!(amt("-0.5").to_string(), "-0.5");
+        assert_eq!(Amount::ZERO.to_string(), "0.0");
+    }
+
+    #[test]
+    /// Summing many small amounts must not drift like `f64` does.
+    fn exact_sum() {
+        let mut total = Amount::ZERO;
+        for _ in 0..1_000_000 {
+            total += amt("0.0001");
+        }
+        assert_eq!(total, amt("100"));
+    }
+}
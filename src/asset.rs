@@ -0,0 +1,142 @@
+//! Identifies what an amount of money is counted in
+use crate::error::{Error, Result};
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Longest code an asset can have
+pub const MAX_LEN: usize = 8;
+
+/// The code of an asset, such as `USD` or `BTC`.
+///
+/// Codes are up to 8 ASCII letters or digits, kept inline so that an asset is
+/// as cheap to copy as an integer. Inputs without an asset column are counted
+/// in the default asset, whose code is empty.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Asset([u8; MAX_LEN]);
+
+impl Asset {
+    /// Parse an asset code from ASCII bytes, see `from_str()`.
+    ///
+    /// Returns `None` if `s` is not a valid code.
+    pub fn from_ascii(s: &[u8]) -> Option<Self> {
+        if s.len() > MAX_LEN || !s.iter().all(|b| b.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let mut code = [0; MAX_LEN];
+        code[..s.len()].copy_from_slice(s);
+        Some(Asset(code))
+    }
+
+    /// The code of the asset, empty for the default asset
+    pub fn code(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(MAX_LEN);
+        // only ever holds ASCII, see `from_ascii()`
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+
+    /// Checks if this is the asset of transactions that do not name one
+    pub fn is_default(&self) -> bool {
+        *self == Asset::default()
+    }
+
+    /// The code packed in an integer, to be stored
+    pub(crate) fn to_bits(self) -> u64 {
+        u64::from_le_bytes(self.0)
+    }
+
+    /// Unpack an asset stored with `to_bits()`
+    pub(crate) fn from_bits(bits: u64) -> Self {
+        Asset(bits.to_le_bytes())
+    }
+}
+
+impl FromStr for Asset {
+    type Err = Error;
+
+    /// Parse a code such as `USD`. An empty code is the default asset.
+    fn from_str(s: &str) -> Result<Self> {
+        Asset::from_ascii(s.as_bytes()).ok_or_else(|| Error::InvalidAsset(s.to_string()))
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl fmt::Debug for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Asset({:?})", self.code())
+    }
+}
+
+impl Serialize for Asset {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Asset {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AssetVisitor)
+    }
+}
+
+struct AssetVisitor;
+
+impl<'de> Visitor<'de> for AssetVisitor {
+    type Value = Asset;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an asset code of up to {} letters or digits", MAX_LEN)
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Asset, E>
+    where
+        E: de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let usd: Asset = "USD".parse().unwrap();
+        assert_eq!(usd.code(), "USD");
+        assert_eq!(usd.to_string(), "USD");
+        assert!(!usd.is_default());
+        assert_eq!(Asset::from_bits(usd.to_bits()), usd);
+        assert!("".parse::<Asset>().unwrap().is_default());
+        assert_eq!("ABCD1234".parse::<Asset>().unwrap().code(), "ABCD1234");
+        for invalid in &["ABCD12345", "US D", "U$D", "€"] {
+            match invalid.parse::<Asset>() {
+                Err(Error::InvalidAsset(s)) => assert_eq!(&s, invalid),
+                otherwise => panic!("{:?}", otherwise),
+            }
+        }
+    }
+
+    #[test]
+    fn serde() {
+        let usd: Asset = "USD".parse().unwrap();
+        assert_eq!(serde_json::to_string(&usd).unwrap(), "\"USD\"");
+        let back: Asset = serde_json::from_str("\"USD\"").unwrap();
+        assert_eq!(back, usd);
+        assert!(serde_json::from_str::<Asset>("\"U.S.D\"").is_err());
+    }
+}
@@ -1,101 +1,235 @@
 //! Represents the Client data structure
+use crate::amount::Amount;
+use crate::asset::Asset;
 use crate::error::{Error, Result};
+use crate::risk::Activity;
+use crate::storage::{DenseWallets, WalletStore};
 
-use serde::ser::{Serialize, SerializeStruct, Serializer};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
-/// Represents a Client's state
-#[derive(Debug)]
-pub struct Client {
-    id: u16,
-    available_balance: f64,
-    held_balance: f64,
-    total_balance: f64,
-    locked: bool,
-}
-
-impl Serialize for Client {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut client = serializer.serialize_struct("Client", 5)?;
-        client.serialize_field("client", &self.id)?;
-        client.serialize_field(
-            "available",
-            &((self.available_balance * 10000.0).round() / 10000.0),
-        )?;
-        client.serialize_field("held", &((self.held_balance * 10000.0).round() / 10000.0))?;
-        client.serialize_field("total", &((self.total_balance * 10000.0).round() / 10000.0))?;
-        client.serialize_field("locked", &self.locked)?;
-        client.end()
-    }
+/// The funds of a client in a single asset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    asset: Asset,
+    available: Amount,
+    held: Amount,
+    total: Amount,
 }
 
-impl Client {
-    pub fn with_id(id: u16) -> Self {
-        let c = Client {
-            id,
-            available_balance: 0.0,
-            held_balance: 0.0,
-            total_balance: 0.0,
-            locked: false,
-        };
-        c
+impl Balance {
+    /// An empty balance in asset `asset`
+    pub fn new(asset: Asset) -> Self {
+        Balance {
+            asset,
+            ..Balance::default()
+        }
     }
 
-    /// Add funds to the client's balance
-    /// This function updates the client's available and total balance
-    pub fn credit(&mut self, amount: f64) {
-        self.available_balance += amount;
-        self.total_balance += amount;
+    /// Add funds to the balance
+    /// This function updates the available and total balance
+    pub fn credit(&mut self, amount: Amount) -> Result<()> {
+        let available = add(self.available, amount)?;
+        self.total = add(self.total, amount)?;
+        self.available = available;
+        Ok(())
     }
 
-    /// Take away from the client's balance
-    /// This function updates the client's available and total balance
+    /// Take away from the balance
+    /// This function updates the available and total balance
     ///
     /// # Note
     /// This function does prevent debiting more than the available balance
-    pub fn debit(&mut self, amount: f64) -> Result<()> {
-        if amount > self.available_balance {
+    pub fn debit(&mut self, amount: Amount) -> Result<()> {
+        if amount > self.available {
             return Err(Error::InssuficientFunds);
         }
-        self.available_balance -= amount;
-        self.total_balance -= amount;
+        let available = sub(self.available, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.available = available;
         Ok(())
     }
 
-    /// Put some of the client's funds in holding
-    /// This function updates the client's available, held and total balance
-    pub fn hold(&mut self, amount: f64) -> Result<()> {
-        if amount > self.available_balance {
+    /// Put some of the funds in holding
+    /// This function updates the available, held and total balance
+    pub fn hold(&mut self, amount: Amount) -> Result<()> {
+        if amount > self.available {
             return Err(Error::InssuficientFunds);
         }
-        self.available_balance -= amount;
-        self.held_balance += amount;
+        let available = sub(self.available, amount)?;
+        self.held = add(self.held, amount)?;
+        self.available = available;
+        Ok(())
+    }
+
+    /// Put funds that had left the account in holding, like when a withdrawal
+    /// is disputed.
+    /// This function updates the held and total balance
+    pub fn hold_reversal(&mut self, amount: Amount) -> Result<()> {
+        let held = add(self.held, amount)?;
+        self.total = add(self.total, amount)?;
+        self.held = held;
         Ok(())
     }
 
     /// Put funds from holding back into the available balance
-    pub fn release(&mut self, amount: f64) -> Result<()> {
-        if self.held_balance < amount {
+    pub fn release(&mut self, amount: Amount) -> Result<()> {
+        if self.held < amount {
             return Err(Error::InssuficientFunds);
         }
-        self.held_balance -= amount;
-        self.available_balance += amount;
+        let held = sub(self.held, amount)?;
+        self.available = add(self.available, amount)?;
+        self.held = held;
         Ok(())
     }
 
     /// Remove `amount` of funds from holding, deceasing total balance.
-    pub fn confiscate(&mut self, amount: f64) -> Result<()> {
-        if self.held_balance < amount {
+    pub fn confiscate(&mut self, amount: Amount) -> Result<()> {
+        if self.held < amount {
             return Err(Error::InssuficientFunds);
         }
-        self.held_balance -= amount;
-        self.total_balance -= amount;
+        let held = sub(self.held, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.held = held;
         Ok(())
     }
 
+    /// The asset the funds are counted in
+    pub fn asset(&self) -> Asset {
+        self.asset
+    }
+
+    /// Funds the client can use
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Funds held by open disputes
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Available and held funds
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+}
+
+/// `a + b`, if it fits an `Amount`
+fn add(a: Amount, b: Amount) -> Result<Amount> {
+    a.checked_add(b).ok_or(Error::AmountOverflow)
+}
+
+/// `a - b`, if it fits an `Amount`
+fn sub(a: Amount, b: Amount) -> Result<Amount> {
+    a.checked_sub(b).ok_or(Error::AmountOverflow)
+}
+
+/// Represents a Client's state
+///
+/// A client has a balance in every asset they moved funds in. Methods that do
+/// not name an asset act on the default asset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Client {
+    #[serde(rename = "client")]
+    id: u16,
+    /// sorted by asset
+    balances: Vec<Balance>,
+    locked: bool,
+    /// What the risk rules remember of the client, see `Activity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    activity: Option<Box<Activity>>,
+}
+
+impl Client {
+    pub fn with_id(id: u16) -> Self {
+        Client {
+            id,
+            balances: Vec::new(),
+            locked: false,
+            activity: None,
+        }
+    }
+
+    /// The balance of the client in asset `asset`, empty if they never moved
+    /// funds in it
+    pub fn balance(&self, asset: Asset) -> Balance {
+        match self.balances.binary_search_by_key(&asset, |b| b.asset) {
+            Ok(i) => self.balances[i],
+            Err(_) => Balance::new(asset),
+        }
+    }
+
+    /// Every balance of the client, sorted by asset
+    pub fn balances(&self) -> &[Balance] {
+        &self.balances
+    }
+
+    /// Get the balance of the client in asset `asset` to modify it, creating an
+    /// empty one if needed
+    pub fn balance_mut(&mut self, asset: Asset) -> &mut Balance {
+        let i = match self.balances.binary_search_by_key(&asset, |b| b.asset) {
+            Ok(i) => i,
+            Err(i) => {
+                self.balances.insert(i, Balance::new(asset));
+                i
+            }
+        };
+        &mut self.balances[i]
+    }
+
+    /// Apply `f` to the balance of the client in asset `asset`.
+    ///
+    /// A balance in a new asset is only kept if `f` succeeds, so that a
+    /// rejected transaction does not leave an empty balance behind.
+    pub fn update<T>(
+        &mut self,
+        asset: Asset,
+        f: impl FnOnce(&mut Balance) -> Result<T>,
+    ) -> Result<T> {
+        match self.balances.binary_search_by_key(&asset, |b| b.asset) {
+            Ok(i) => f(&mut self.balances[i]),
+            Err(i) => {
+                let mut balance = Balance::new(asset);
+                let applied = f(&mut balance)?;
+                self.balances.insert(i, balance);
+                Ok(applied)
+            }
+        }
+    }
+
+    /// Add funds to the client's balance, see `Balance::credit()`
+    pub fn credit(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.credit(amount))
+    }
+
+    /// Take away from the client's balance, see `Balance::debit()`
+    pub fn debit(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.debit(amount))
+    }
+
+    /// Put some of the client's funds in holding, see `Balance::hold()`
+    pub fn hold(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.hold(amount))
+    }
+
+    /// Put funds that had left the account in holding, see
+    /// `Balance::hold_reversal()`
+    pub fn hold_reversal(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.hold_reversal(amount))
+    }
+
+    /// Put funds from holding back into the available balance, see
+    /// `Balance::release()`
+    pub fn release(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.release(amount))
+    }
+
+    /// Remove `amount` of funds from holding, see `Balance::confiscate()`
+    pub fn confiscate(&mut self, amount: Amount) -> Result<()> {
+        self.update(Asset::default(), |b| b.confiscate(amount))
+    }
+
     /// Get the client's id
     pub fn id(&self) -> u16 {
         self.id
@@ -106,50 +240,95 @@ impl Client {
         self.locked = true;
     }
 
-    #[cfg(test)]
-    pub fn available_balance(&self) -> f64 {
-        self.available_balance
+    /// Unlock a client, allowing it to move funds again
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    /// Check if the client's account is locked
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The recent deposits and withdrawals of the client, if some limit needs
+    /// them
+    pub fn activity(&self) -> Option<&Activity> {
+        self.activity.as_deref()
+    }
+
+    /// Get the activity of the client to modify it, creating it if needed
+    pub(crate) fn activity_mut(&mut self) -> &mut Activity {
+        self.activity.get_or_insert_with(Box::default)
+    }
+
+    /// Funds the client can use, in the default asset
+    pub fn available_balance(&self) -> Amount {
+        self.balance(Asset::default()).available()
     }
-    #[cfg(test)]
-    pub fn held_balance(&self) -> f64 {
-        self.held_balance
+
+    /// Funds held by open disputes, in the default asset
+    pub fn held_balance(&self) -> Amount {
+        self.balance(Asset::default()).held()
     }
-    #[cfg(test)]
-    pub fn total_balance(&self) -> f64 {
-        self.total_balance
+
+    /// Available and held funds, in the default asset
+    pub fn total_balance(&self) -> Amount {
+        self.balance(Asset::default()).total()
     }
 }
 
-#[derive(Debug)]
-pub struct ClientWallets {
-    wallets: HashMap<u16, Client>,
+/// The wallets of every client the engine heard of.
+///
+/// Wallets are kept in a `WalletStore`, a `DenseWallets` unless created
+/// `with_store()`.
+#[derive(Debug, Default)]
+pub struct ClientWallets<S: WalletStore = DenseWallets> {
+    wallets: S,
 }
 
 impl ClientWallets {
     pub fn new() -> Self {
-        ClientWallets {
-            wallets: HashMap::new(),
-        }
+        Self::default()
     }
+}
 
-    pub fn get_or_create_mut(&mut self, client_id: u16) -> &mut Client {
-        self.wallets
-            .entry(client_id)
-            .or_insert_with(|| Client::with_id(client_id))
+impl<S: WalletStore> ClientWallets<S> {
+    /// Keep the wallets in `store`
+    pub fn with_store(store: S) -> Self {
+        ClientWallets { wallets: store }
     }
 
-    pub fn print_balances(&self) -> Result<()> {
-        let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    pub fn get_or_create_mut(&mut self, client_id: u16) -> &mut Client {
+        self.wallets.get_or_create_mut(client_id)
+    }
 
-        // We sort here to have a consistent output order.
-        // This allows for easier testing, as more predictable.
-        let mut sorted: Vec<(&u16, &Client)> = self.wallets.iter().collect();
-        sorted.sort_by_key(|(_, c)| c.id());
-        for (_, client) in &sorted {
-            wtr.serialize(client)?;
+    /// Unlock the account of client `client_id`.
+    ///
+    /// Meant for support staff, once a locked account has been reviewed.
+    /// Returns `false` if the client is unknown.
+    pub fn unlock(&mut self, client_id: u16) -> bool {
+        match self.wallets.get_mut(client_id) {
+            Some(client) => {
+                client.unlock();
+                true
+            }
+            None => false,
         }
-        wtr.flush()?;
-        Ok(())
+    }
+
+    /// Get the wallet of client `client_id`, if it exists
+    pub fn get(&self, client_id: u16) -> Option<&Client> {
+        self.wallets.get(client_id)
+    }
+
+    /// List every client, sorted by id.
+    ///
+    /// We sort here to have a consistent output order.
+    /// This allows for easier testing, as more predictable.
+    pub fn clients(&self) -> Vec<&Client> {
+        let mut sorted: Vec<&Client> = self.wallets.wallets().collect();
+        sorted.sort_by_key(|c| c.id());
+        sorted
     }
 }
 
@@ -157,15 +336,18 @@ impl ClientWallets {
 mod test {
     use super::*;
 
+    fn amt(s: &str) -> Amount {
+        s.parse().expect("valid amount")
+    }
+
     fn base_client() -> Client {
         Client::with_id(1)
     }
 
     /// construct a new client with a balance of `balance`
-    fn base_client_with_funds(funds: f64) -> Client {
+    fn base_client_with_funds(funds: &str) -> Client {
         let mut c = Client::with_id(1);
-        c.available_balance += funds;
-        c.total_balance += funds;
+        c.credit(amt(funds)).unwrap();
         c
     }
 
@@ -173,93 +355,166 @@ mod test {
     /// Test that we credit a client the right amount.
     fn credit() {
         let mut client = base_client();
-        client.credit(1.88889);
-        assert_eq!(client.available_balance(), 1.88889);
-        assert_eq!(client.total_balance(), 1.88889);
+        client.credit(amt("1.8888")).unwrap();
+        assert_eq!(client.available_balance(), amt("1.8888"));
+        assert_eq!(client.total_balance(), amt("1.8888"));
     }
 
     #[test]
     fn debit_no_funds() {
         let mut client = base_client();
-        match client.debit(1.88889) {
+        match client.debit(amt("1.8888")) {
             Err(Error::InssuficientFunds) => (),
             otherwise => panic!("{:?}", otherwise),
         }
-        assert_eq!(client.available_balance(), 0.0);
-        assert_eq!(client.total_balance(), 0.0);
+        assert_eq!(client.available_balance(), amt("0.0"));
+        assert_eq!(client.total_balance(), amt("0.0"));
     }
 
     #[test]
     fn debit_too_much() {
-        let mut client = base_client_with_funds(19.0);
-        match client.debit(50.9) {
+        let mut client = base_client_with_funds("19.0");
+        match client.debit(amt("50.9")) {
             Err(Error::InssuficientFunds) => (),
             otherwise => panic!("{:?}", otherwise),
         }
-        assert_eq!(client.available_balance(), 19.0);
-        assert_eq!(client.total_balance(), 19.0);
+        assert_eq!(client.available_balance(), amt("19.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
     }
 
     #[test]
     fn debit() {
-        let mut client = base_client_with_funds(19.0);
-        client.debit(10.9).expect("Debit should have went through");
-        assert_eq!(client.available_balance(), 8.1);
-        assert_eq!(client.total_balance(), 8.1);
+        let mut client = base_client_with_funds("19.0");
+        client
+            .debit(amt("10.9"))
+            .expect("Debit should have went through");
+        assert_eq!(client.available_balance(), amt("8.1"));
+        assert_eq!(client.total_balance(), amt("8.1"));
     }
 
     #[test]
     fn hold() {
-        let mut client = base_client_with_funds(19.0);
+        let mut client = base_client_with_funds("19.0");
 
         client
-            .hold(10.0)
+            .hold(amt("10.0"))
             .expect("Should have been able to hold funds");
-        assert_eq!(client.held_balance(), 10.0);
-        assert_eq!(client.available_balance(), 9.0);
-        assert_eq!(client.total_balance(), 19.0);
+        assert_eq!(client.held_balance(), amt("10.0"));
+        assert_eq!(client.available_balance(), amt("9.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
     }
 
     #[test]
     fn hold_no_funds() {
-        let mut client = base_client_with_funds(1.0);
+        let mut client = base_client_with_funds("1.0");
 
-        match client.hold(10.0) {
+        match client.hold(amt("10.0")) {
             Err(Error::InssuficientFunds) => (),
             otherwise => panic!("{:?}", otherwise),
         };
-        assert_eq!(client.held_balance(), 0.0);
-        assert_eq!(client.available_balance(), 1.0);
-        assert_eq!(client.total_balance(), 1.0);
+        assert_eq!(client.held_balance(), amt("0.0"));
+        assert_eq!(client.available_balance(), amt("1.0"));
+        assert_eq!(client.total_balance(), amt("1.0"));
     }
 
     #[test]
     fn release() {
-        let mut client = base_client_with_funds(19.0);
+        let mut client = base_client_with_funds("19.0");
 
         client
-            .hold(10.0)
+            .hold(amt("10.0"))
             .expect("Should have been able to hold funds");
-        assert_eq!(client.held_balance(), 10.0);
-        assert_eq!(client.available_balance(), 9.0);
-        assert_eq!(client.total_balance(), 19.0);
+        assert_eq!(client.held_balance(), amt("10.0"));
+        assert_eq!(client.available_balance(), amt("9.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
         client
-            .release(10.0)
+            .release(amt("10.0"))
             .expect("Should have been able to release funds");
-        assert_eq!(client.held_balance(), 0.0);
-        assert_eq!(client.available_balance(), 19.0);
-        assert_eq!(client.total_balance(), 19.0);
+        assert_eq!(client.held_balance(), amt("0.0"));
+        assert_eq!(client.available_balance(), amt("19.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
+    }
+
+    #[test]
+    fn hold_reversal() {
+        let mut client = base_client_with_funds("9.0");
+
+        client.hold_reversal(amt("10.0")).unwrap();
+        assert_eq!(client.held_balance(), amt("10.0"));
+        assert_eq!(client.available_balance(), amt("9.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
+    }
+
+    #[test]
+    fn overflow() {
+        let max = Amount::from_units(i64::MAX);
+        let mut client = base_client_with_funds("1.0");
+        for result in [
+            client.credit(max),
+            client.hold_reversal(max),
+            client.update(Asset::default(), |b| {
+                b.hold(amt("1.0"))?;
+                b.hold_reversal(max)
+            }),
+        ] {
+            match result {
+                Err(Error::AmountOverflow) => (),
+                otherwise => panic!("{:?}", otherwise),
+            }
+        }
+        // nothing changed but the funds put on hold
+        assert_eq!(client.held_balance(), amt("1.0"));
+        assert_eq!(client.total_balance(), amt("1.0"));
     }
 
     #[test]
     fn release_no_funds() {
-        let mut client = base_client_with_funds(19.0);
-        match client.release(10.0) {
+        let mut client = base_client_with_funds("19.0");
+        match client.release(amt("10.0")) {
             Err(Error::InssuficientFunds) => (),
             otherwise => panic!("{:?}", otherwise),
         }
-        assert_eq!(client.held_balance(), 0.0);
-        assert_eq!(client.available_balance(), 19.0);
-        assert_eq!(client.total_balance(), 19.0);
+        assert_eq!(client.held_balance(), amt("0.0"));
+        assert_eq!(client.available_balance(), amt("19.0"));
+        assert_eq!(client.total_balance(), amt("19.0"));
+    }
+
+    #[test]
+    fn assets() {
+        let usd = "USD".parse().unwrap();
+        let mut client = base_client_with_funds("1.0");
+        client
+            .update(usd, |b| b.debit(amt("1.0")))
+            .expect_err("no USD yet");
+        assert_eq!(client.balances().len(), 1);
+        client.balance_mut(usd).credit(amt("2.0")).unwrap();
+        client.update(usd, |b| b.hold(amt("0.5"))).unwrap();
+        assert_eq!(client.balance(usd).available(), amt("1.5"));
+        assert_eq!(client.balance(usd).held(), amt("0.5"));
+        assert_eq!(client.available_balance(), amt("1.0"));
+        let assets: Vec<_> = client.balances().iter().map(|b| b.asset()).collect();
+        assert_eq!(assets, vec![Asset::default(), usd]);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut client = base_client_with_funds("10.1234");
+        client.hold(amt("2.5")).unwrap();
+        client.lock();
+        let json = serde_json::to_string(&client).unwrap();
+        let back: Client = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.id(), 1);
+        assert_eq!(back.available_balance(), amt("7.6234"));
+        assert_eq!(back.held_balance(), amt("2.5"));
+        assert_eq!(back.total_balance(), amt("10.1234"));
+        assert!(back.is_locked());
+
+        client
+            .balance_mut("BTC".parse().unwrap())
+            .credit(amt("1"))
+            .unwrap();
+        let json = serde_json::to_string(&client).unwrap();
+        let back: Client = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.balances(), client.balances());
     }
 }
@@ -1,7 +1,161 @@
+use std::str::FromStr;
 use std::time::Instant;
+use thiserror::Error;
 
+use crate::amount::Amount;
 use crate::client::{Client, ClientWallets};
-use crate::transaction::{Transaction, TransactionLog, Type::*};
+use crate::error::Error;
+use crate::fee::Fees;
+use crate::risk::{Limit, RiskRules};
+use crate::storage::{DenseTxStore, DenseWallets, TxStore, WalletStore};
+use crate::transaction::{
+    DisputeEvent, DisputeState, DisputeWindow, InvalidTransition, Transaction, TransactionLog,
+    Type, Type::*,
+};
+
+/// What a locked account is still allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Refuse every transaction issued by a locked client. Disputes open when
+    /// the account got locked stay open until it is unlocked.
+    RejectAll,
+    /// Only accept resolve and chargeback on a locked account, so that the
+    /// disputes open when it got locked can still be closed
+    #[default]
+    SettleDisputes,
+    /// Only accept deposits on a locked account
+    AllowDeposits,
+    /// Only accept dispute, resolve and chargeback on a locked account
+    AllowDisputes,
+}
+
+impl LockPolicy {
+    /// Checks if a locked account may execute a transaction of type `ty`
+    pub fn allows(&self, ty: &Type) -> bool {
+        match self {
+            LockPolicy::RejectAll => false,
+            LockPolicy::SettleDisputes => matches!(ty, Resolve | Chargeback),
+            LockPolicy::AllowDeposits => matches!(ty, Deposit),
+            LockPolicy::AllowDisputes => matches!(ty, Dispute | Resolve | Chargeback),
+        }
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "reject-all" => Ok(LockPolicy::RejectAll),
+            "settle-disputes" => Ok(LockPolicy::SettleDisputes),
+            "allow-deposits" => Ok(LockPolicy::AllowDeposits),
+            "allow-disputes" => Ok(LockPolicy::AllowDisputes),
+            other => Err(Error::UnknownLockPolicy(other.to_string())),
+        }
+    }
+}
+
+/// Settings that change how the engine treats transactions
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub lock_policy: LockPolicy,
+    /// Allow a transaction to be disputed again once its dispute was resolved.
+    /// Off by default: a transaction can only be disputed once.
+    pub allow_redispute: bool,
+    /// How long a transaction can be disputed, see `TransactionLog`
+    pub dispute_window: DisputeWindow,
+    /// What transactions cost, see `Fees`
+    pub fees: Fees,
+    /// Limits on deposits and withdrawals, see `RiskRules`
+    pub risk: RiskRules,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            lock_policy: LockPolicy::default(),
+            allow_redispute: false,
+            dispute_window: DisputeWindow::Unbounded,
+            fees: Fees::default(),
+            risk: RiskRules::default(),
+        }
+    }
+}
+
+/// What the engine did when executing a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applied {
+    Deposited,
+    Withdrew,
+    Transferred,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// Why the engine refused to execute a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Rejection {
+    #[error("transaction id was already used")]
+    DuplicateTxId,
+    #[error("amount must be positive")]
+    InvalidAmount,
+    #[error("insufficient funds")]
+    InsufficientFunds,
+    #[error("referenced transaction does not exist")]
+    UnknownTx,
+    #[error("referenced transaction belongs to another client")]
+    ClientMismatch,
+    #[error("referenced transaction is not under dispute")]
+    NotDisputed,
+    #[error("referenced transaction is already under dispute")]
+    AlreadyDisputed,
+    #[error("referenced transaction can no longer be disputed")]
+    DisputeClosed,
+    #[error("referenced transaction is out of the dispute window")]
+    DisputeWindowExpired,
+    #[error("account is locked")]
+    AccountLocked,
+    #[error("transfer has no valid recipient")]
+    InvalidRecipient,
+    #[error("recipient's account is locked")]
+    RecipientLocked,
+    #[error("fees need the wallet of the house account")]
+    NoHouseAccount,
+    #[error("{0} limit exceeded")]
+    LimitExceeded(Limit),
+    #[error("amount out of range")]
+    AmountOverflow,
+}
+
+impl From<InvalidTransition> for Rejection {
+    fn from(e: InvalidTransition) -> Self {
+        match e {
+            InvalidTransition::AlreadyDisputed => Rejection::AlreadyDisputed,
+            InvalidTransition::NotDisputed => Rejection::NotDisputed,
+            InvalidTransition::Closed => Rejection::DisputeClosed,
+        }
+    }
+}
+
+impl From<Error> for Rejection {
+    /// Operations on balances only fail for lack of funds, or when an amount
+    /// gets out of range
+    fn from(e: Error) -> Self {
+        match e {
+            Error::AmountOverflow => Rejection::AmountOverflow,
+            _ => Rejection::InsufficientFunds,
+        }
+    }
+}
+
+/// `a + b`, if it fits an `Amount`
+fn sum(a: Amount, b: Amount) -> Result<Amount, Rejection> {
+    a.checked_add(b).ok_or(Rejection::AmountOverflow)
+}
+
+/// The result of executing a single transaction
+pub type Outcome = Result<Applied, Rejection>;
 
 pub fn run<S: Iterator>(
     transactions: &mut S, /*stream of transactions*/
@@ -9,8 +163,18 @@ pub fn run<S: Iterator>(
 where
     S::Item: Into<Transaction>,
 {
-    let mut tx_log = TransactionLog::new();
-    let mut wallets = ClientWallets::new();
+    run_with_config(&Config::default(), transactions)
+}
+
+/// Same as `run`, but lets the caller choose the engine's settings
+pub fn run_with_config<S: Iterator>(
+    config: &Config,
+    transactions: &mut S, /*stream of transactions*/
+) -> (ClientWallets, TransactionLog)
+where
+    S::Item: Into<Transaction>,
+{
+    let mut engine = Engine::with_config(config.clone());
     let total = Instant::now();
     let total_tx_count = transactions.size_hint().1.unwrap_or(1) as u128;
 
@@ -18,8 +182,9 @@ where
         // TODO activate only when profiling ?
         let single = Instant::now();
         let t = t.into();
-        let mut client = wallets.get_or_create_mut(t.client);
-        execute_transaction(&t, &mut tx_log, &mut client);
+        if let Err(rejection) = engine.submit(&t) {
+            log::debug!("rejected transaction {} ({})", t.id, rejection);
+        }
         log::trace!(
             "Took {}ns to process transaction",
             single.elapsed().as_nanos()
@@ -30,158 +195,645 @@ where
         "Took ~{}ns per transaction",
         total.elapsed().as_nanos() / total_tx_count
     );
-    (wallets, tx_log)
+    engine.into_parts()
+}
+
+/// A long-lived engine, fed transactions as they arrive.
+///
+/// The engine owns the clients' wallets and the transaction log, and can be
+/// queried between two transactions.
+#[derive(Debug)]
+pub struct Engine<W: WalletStore = DenseWallets, S: TxStore = DenseTxStore> {
+    config: Config,
+    wallets: ClientWallets<W>,
+    tx_log: TransactionLog<S>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::with_config(Config::default())
+    }
+}
+
+impl Engine {
+    /// Create an engine with the default settings, that knows no client yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an engine with the given settings, that knows no client yet
+    pub fn with_config(config: Config) -> Self {
+        let tx_log = TransactionLog::with_window(config.dispute_window);
+        Self::from_parts(config, ClientWallets::new(), tx_log)
+    }
+}
+
+impl<W: WalletStore, S: TxStore> Engine<W, S> {
+    /// Create an engine that picks up from existing wallets and log, see
+    /// `snapshot` and `journal`
+    pub fn from_parts(
+        config: Config,
+        wallets: ClientWallets<W>,
+        tx_log: TransactionLog<S>,
+    ) -> Self {
+        Engine {
+            config,
+            wallets,
+            tx_log,
+        }
+    }
+
+    /// Stop the engine, and get back its wallets and log
+    pub fn into_parts(self) -> (ClientWallets<W>, TransactionLog<S>) {
+        (self.wallets, self.tx_log)
+    }
+
+    /// The settings of the engine
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Execute a single transaction, see `process()`
+    pub fn submit(&mut self, t: &Transaction) -> Outcome {
+        process(&self.config, t, &mut self.wallets, &mut self.tx_log)
+    }
+
+    /// Execute `t`, the `tick`-th transaction submitted to a parallel engine.
+    ///
+    /// `foreign` is the rejection the transaction gets because of a
+    /// transaction that lives on another shard, see `parallel`. It still
+    /// creates the client's wallet, and is still refused first if the client's
+    /// account is locked, like any other rejected transaction.
+    pub(crate) fn submit_at(
+        &mut self,
+        tick: u64,
+        t: &Transaction,
+        foreign: Option<Rejection>,
+    ) -> Outcome {
+        self.advance_to(tick - 1);
+        match foreign {
+            None => self.submit(t),
+            Some(rejection) => {
+                self.tx_log.tick();
+                let client = self.wallets.get_or_create_mut(t.client);
+                check_lock(&self.config, t, client)?;
+                Err(rejection)
+            }
+        }
+    }
+
+    /// Execute `t`, the `tick`-th transaction submitted to a parallel engine,
+    /// whose counterparty lives on another shard, see `counterparty()`.
+    ///
+    /// `counterparty` is the wallet of that client, and is only changed if the
+    /// transaction is applied.
+    pub(crate) fn submit_with_at(
+        &mut self,
+        tick: u64,
+        t: &Transaction,
+        counterparty: &mut Client,
+    ) -> Outcome {
+        self.advance_to(tick - 1);
+        let target = start(t, &mut self.tx_log);
+        let client = self.wallets.get_or_create_mut(t.client);
+        let mut updated = counterparty.clone();
+        let outcome = execute(
+            &self.config,
+            t,
+            target,
+            &mut self.tx_log,
+            client,
+            std::slice::from_mut(&mut updated),
+        );
+        if outcome.is_ok() {
+            *counterparty = updated;
+        }
+        outcome
+    }
+
+    /// The client other than the issuer whose wallet `t` would change, see
+    /// `counterparty()`
+    pub(crate) fn counterparty(&self, t: &Transaction) -> Option<u16> {
+        counterparty(t, &self.tx_log)
+    }
+
+    /// Replace the wallet of client `client.id()`
+    pub(crate) fn set_client(&mut self, client: Client) {
+        let id = client.id();
+        *self.wallets.get_or_create_mut(id) = client;
+    }
+
+    /// Move the logical clock of the log to `tick`, see
+    /// `TransactionLog::advance_to()`
+    pub(crate) fn advance_to(&mut self, tick: u64) {
+        self.tx_log.advance_to(tick);
+    }
+
+    /// Execute transactions in order, and get the outcome of each of them
+    pub fn submit_batch<'a, I>(&mut self, batch: I) -> Vec<Outcome>
+    where
+        I: IntoIterator<Item = &'a Transaction>,
+    {
+        batch.into_iter().map(|t| self.submit(t)).collect()
+    }
+
+    /// Get the wallet of client `client_id`, if we heard of them
+    pub fn client(&self, client_id: u16) -> Option<&Client> {
+        self.wallets.get(client_id)
+    }
+
+    /// Get the logged deposit, withdrawal or transfer with id `tx_id`, along with where
+    /// it stands in the dispute process
+    pub fn transaction(&self, tx_id: u32) -> Option<Transaction> {
+        self.tx_log.find(tx_id)
+    }
+
+    /// The wallet of every client, sorted by id
+    pub fn balances(&self) -> Vec<&Client> {
+        self.wallets.clients()
+    }
+
+    /// Unlock the account of client `client_id`, see `ClientWallets::unlock()`
+    pub fn unlock(&mut self, client_id: u16) -> bool {
+        self.wallets.unlock(client_id)
+    }
+
+    /// The wallets of the clients
+    pub fn wallets(&self) -> &ClientWallets<W> {
+        &self.wallets
+    }
+
+    /// The log of the transactions
+    pub fn tx_log(&self) -> &TransactionLog<S> {
+        &self.tx_log
+    }
+}
+
+/// Execute a single transaction against the wallet of the client that issued it,
+/// and the wallets of the other clients it changes, see `others_of()`.
+///
+/// The client's wallet is created if this is the first time we hear of them,
+/// even if the transaction ends up rejected. The other wallets are only
+/// created, or changed, if the transaction is applied.
+pub fn process<W: WalletStore, S: TxStore>(
+    config: &Config,
+    t: &Transaction,
+    wallets: &mut ClientWallets<W>,
+    tx_log: &mut TransactionLog<S>,
+) -> Outcome {
+    let target = start(t, tx_log);
+    // copies of the other wallets, written back if `t` is applied
+    let mut others = [Client::with_id(0), Client::with_id(0)];
+    let mut len = 0;
+    for id in others_of(config, t, target.as_ref()).iter().flatten() {
+        others[len] = wallets
+            .get(*id)
+            .cloned()
+            .unwrap_or_else(|| Client::with_id(*id));
+        len += 1;
+    }
+    let client = wallets.get_or_create_mut(t.client);
+    let outcome = execute(config, t, target, tx_log, client, &mut others[..len]);
+    if outcome.is_ok() {
+        for other in &mut others[..len] {
+            let id = other.id();
+            *wallets.get_or_create_mut(id) = std::mem::replace(other, Client::with_id(id));
+        }
+    }
+    outcome
+}
+
+/// Move the clock of the log to the tick of `t`, and find the transaction `t`
+/// targets if it is a dispute event, once older transactions aged out
+fn start<S: TxStore>(t: &Transaction, tx_log: &mut TransactionLog<S>) -> Option<Transaction> {
+    tx_log.tick();
+    match t.r#type {
+        Dispute | Resolve | Chargeback => tx_log.find(t.id),
+        Deposit | Withdrawal | Transfer => None,
+    }
+}
+
+/// The clients other than the issuer whose wallet `t` may change: its
+/// counterparty, see `counterparty()`, and the house account if `t` involves a
+/// fee. `target` is the transaction `t` targets, see `start()`.
+fn others_of(config: &Config, t: &Transaction, target: Option<&Transaction>) -> [Option<u16>; 2] {
+    let (to, fee) = match t.r#type {
+        Transfer => (t.to, false),
+        Deposit | Withdrawal => (None, fee_of(config, t) > Amount::ZERO),
+        Dispute | Resolve | Chargeback => match target {
+            Some(logged) => {
+                let fee = match t.r#type {
+                    Chargeback => config.fees.of(&Chargeback, t.client, logged.amount),
+                    _ => Amount::ZERO,
+                };
+                (logged.to, logged.fee() > Amount::ZERO || fee > Amount::ZERO)
+            }
+            None => (None, false),
+        },
+    };
+    let to = to.filter(|id| *id != t.client);
+    let house = Some(config.fees.house).filter(|id| fee && *id != t.client && Some(*id) != to);
+    [to, house]
+}
+
+/// The client other than the issuer whose wallet `t` changes: the recipient
+/// of a transfer, or the recipient of the transfer a dispute event targets
+fn counterparty<S: TxStore>(t: &Transaction, tx_log: &TransactionLog<S>) -> Option<u16> {
+    let to = match t.r#type {
+        Transfer => t.to,
+        Dispute | Resolve | Chargeback => tx_log.find(t.id).and_then(|logged| logged.to),
+        Deposit | Withdrawal => None,
+    };
+    to.filter(|to| *to != t.client)
+}
+
+/// The fee the issuer of deposit or withdrawal `t` pays. A deposit never pays
+/// more than what it brings.
+fn fee_of(config: &Config, t: &Transaction) -> Amount {
+    let fee = config.fees.of(&t.r#type, t.client, t.amount);
+    match t.r#type {
+        Deposit => fee.min(t.amount.max(Amount::ZERO)),
+        _ => fee,
+    }
 }
 
 /// Run the correct logic for the type of transaction.
-/// If the transaction was valid and successful, it gets added to the TransactionLog
-pub fn execute_transaction(t: &Transaction, tx_log: &mut TransactionLog, client: &mut Client) {
-    let tx_exists = tx_log.contains(t.id);
-    let record_op = match t.r#type {
-        Deposit if !tx_exists => deposit(client, t.amount),
-        Withdrawal if !tx_exists => withdraw(client, t.amount),
-        Dispute => dispute(client, t.id, tx_log),
-        Resolve => resolve(client, t.id, tx_log),
-        Chargeback => chargeback(client, t.id, tx_log),
-        _ => false,
+/// If the transaction was a valid and successful deposit, withdrawal or
+/// transfer, it gets added to the TransactionLog, along with the fee it paid.
+/// Dispute events are recorded in the history of the transaction they target.
+///
+/// Transfers, dispute events on transfers, and transactions involving a fee
+/// also need the wallet of the recipient or of the house account, and are
+/// rejected with `InvalidRecipient` or `NoHouseAccount` here: see `process()`.
+pub fn execute_transaction<S: TxStore>(
+    config: &Config,
+    t: &Transaction,
+    tx_log: &mut TransactionLog<S>,
+    client: &mut Client,
+) -> Outcome {
+    let target = start(t, tx_log);
+    execute(config, t, target, tx_log, client, &mut [])
+}
+
+/// Same as `execute_transaction()`, once the clock of the log moved to the
+/// tick of `t` and `target` was looked up, see `start()`, with the wallets of
+/// the other clients `t` changes, see `others_of()`
+fn execute<S: TxStore>(
+    config: &Config,
+    t: &Transaction,
+    target: Option<Transaction>,
+    tx_log: &mut TransactionLog<S>,
+    client: &mut Client,
+    others: &mut [Client],
+) -> Outcome {
+    check_lock(config, t, client)?;
+    let fee = match t.r#type {
+        Deposit | Withdrawal => fee_of(config, t),
+        _ => Amount::ZERO,
     };
-    if record_op {
-        tx_log.push(t);
+    let applied = match t.r#type {
+        Deposit | Withdrawal | Transfer if tx_log.contains(t.id) => Err(Rejection::DuplicateTxId),
+        Deposit | Withdrawal | Transfer if t.amount <= Amount::ZERO => {
+            Err(Rejection::InvalidAmount)
+        }
+        Deposit => deposit(config, client, others, t, fee, tx_log.now()),
+        Withdrawal => withdraw(config, client, others, t, fee, tx_log.now()),
+        Transfer => transfer(config, client, others, t, tx_log.now()),
+        Dispute => dispute(config, client, others, t.id, target, tx_log),
+        Resolve => resolve(config, client, others, t.id, target, tx_log),
+        Chargeback => chargeback(config, client, others, t.id, target, tx_log),
+    }?;
+    if let Applied::Deposited | Applied::Withdrew | Applied::Transferred = applied {
+        // whatever state the input came with, the transaction was not disputed yet
+        tx_log.push(&t.clone().with_fee(fee).with_state(DisputeState::Processed));
     }
+    Ok(applied)
 }
 
-/// Credit the client's account of `amount` funds.
-fn deposit(client: &mut Client, amount: f64) -> bool {
-    client.credit(amount);
-    log::trace!("deposited {} to client {}'s balance", amount, client.id());
-    true
+/// Make sure `client` may execute `t`, according to the lock policy
+fn check_lock(config: &Config, t: &Transaction, client: &Client) -> Result<(), Rejection> {
+    if client.is_locked() && !config.lock_policy.allows(&t.r#type) {
+        return Err(Rejection::AccountLocked);
+    }
+    Ok(())
 }
 
-/// Withdraw `amount` from the client's account, if there are sufficient funds.
-fn withdraw(client: &mut Client, amount: f64) -> bool {
-    if let Err(_) = client.debit(amount) {
-        log::debug!(
-            "client {} tried to withdraw more than available balance",
-            client.id()
-        );
-        return false;
+/// The wallet of the house account among `others`. Fees are booked to it even
+/// if it is locked.
+fn house<'a>(config: &Config, others: &'a mut [Client]) -> Result<&'a mut Client, Rejection> {
+    others
+        .iter_mut()
+        .find(|c| c.id() == config.fees.house)
+        .ok_or(Rejection::NoHouseAccount)
+}
+
+/// Credit the client's account of `t.amount` funds in `t.asset`, minus `fee`,
+/// which goes to the house, if the client's limits allow it at tick `now`.
+fn deposit(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    t: &Transaction,
+    fee: Amount,
+    now: u64,
+) -> Outcome {
+    config
+        .risk
+        .check_deposit(client, t.asset, t.amount - fee, now)
+        .map_err(Rejection::LimitExceeded)?;
+    if fee > Amount::ZERO {
+        house(config, others)?.update(t.asset, |b| b.credit(fee))?;
+    }
+    client.update(t.asset, |b| b.credit(t.amount - fee))?;
+    config.risk.record_deposit(client, now);
+    log::trace!("deposited {} to client {}'s balance", t.amount, client.id());
+    Ok(Applied::Deposited)
+}
+
+/// Withdraw `t.amount` of `t.asset` from the client's account, plus `fee`,
+/// which goes to the house, if there are sufficient funds and the client's
+/// limits allow it at tick `now`.
+fn withdraw(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    t: &Transaction,
+    fee: Amount,
+    now: u64,
+) -> Outcome {
+    config
+        .risk
+        .check_withdrawal(client, t.asset, t.amount, now)
+        .map_err(Rejection::LimitExceeded)?;
+    let debit = sum(t.amount, fee)?;
+    // the house is only written back if the client can pay
+    if fee > Amount::ZERO {
+        house(config, others)?.update(t.asset, |b| b.credit(fee))?;
     }
-    log::trace!("withdrew {} from client {}'s balance", amount, client.id());
-    true
+    client.update(t.asset, |b| b.debit(debit))?;
+    config
+        .risk
+        .record_withdrawal(client, t.asset, t.amount, now);
+    log::trace!(
+        "withdrew {} from client {}'s balance",
+        t.amount,
+        client.id()
+    );
+    Ok(Applied::Withdrew)
+}
+
+/// Move `t.amount` of `t.asset` from the client's account to the account of
+/// the recipient, if there are sufficient funds. Either both accounts change,
+/// or none does.
+///
+/// The transfer is a withdrawal for the client, and a deposit for the
+/// recipient: it must be within the limits of both at tick `now`. The
+/// recipient's account may be locked only if the lock policy lets it receive
+/// deposits.
+fn transfer(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    t: &Transaction,
+    now: u64,
+) -> Outcome {
+    let recipient = recipient_of(t, others)?;
+    if recipient.is_locked() && !config.lock_policy.allows(&Deposit) {
+        return Err(Rejection::RecipientLocked);
+    }
+    config
+        .risk
+        .check_withdrawal(client, t.asset, t.amount, now)
+        .and_then(|_| config.risk.check_deposit(recipient, t.asset, t.amount, now))
+        .map_err(Rejection::LimitExceeded)?;
+    // the recipient is only written back if the client can pay
+    recipient.update(t.asset, |b| b.credit(t.amount))?;
+    client.update(t.asset, |b| b.debit(t.amount))?;
+    config
+        .risk
+        .record_withdrawal(client, t.asset, t.amount, now);
+    config.risk.record_deposit(recipient, now);
+    log::trace!(
+        "transferred {} from client {} to client {}",
+        t.amount,
+        client.id(),
+        recipient.id()
+    );
+    Ok(Applied::Transferred)
+}
+
+/// The wallet of the recipient of transfer `t` among `others`, if it is not
+/// the wallet of the issuer
+fn recipient_of<'a>(
+    t: &Transaction,
+    others: &'a mut [Client],
+) -> Result<&'a mut Client, Rejection> {
+    let to = t.to.filter(|to| *to != t.client);
+    others
+        .iter_mut()
+        .find(|c| Some(c.id()) == to)
+        .ok_or(Rejection::InvalidRecipient)
+}
+
+/// Check the transaction `tx` targeted by a dispute related request, as found
+/// in the log, see `start()`: make sure it was issued by `client`, and that
+/// `event` is a valid step of its dispute lifecycle.
+///
+/// Returns the transaction, and the dispute state it moves to.
+fn find_disputable<S: TxStore>(
+    config: &Config,
+    client: &Client,
+    tx: u32,
+    target: Option<Transaction>,
+    event: DisputeEvent,
+    tx_hist: &TransactionLog<S>,
+) -> Result<(Transaction, DisputeState), Rejection> {
+    let expired = tx_hist.is_expired(tx);
+    let transaction = target.ok_or(if expired {
+        Rejection::DisputeWindowExpired
+    } else {
+        Rejection::UnknownTx
+    })?;
+    if transaction.client != client.id() {
+        return Err(Rejection::ClientMismatch);
+    }
+    if expired && event == DisputeEvent::Dispute {
+        return Err(Rejection::DisputeWindowExpired);
+    }
+    let next = transaction.state().next(event, config.allow_redispute)?;
+    Ok((transaction, next))
 }
 
 /// Dispute a transaction
 ///
 /// # Notes:
 /// - Disputing an order can only be done by the client that has issued the
-/// target transaction.
+///   target transaction.
 /// - A transaction can only be under dispute once at a time. If a dispute is
-/// opened on a transaction, subsequent disputes will have no effect.
+///   opened on a transaction, subsequent disputes will have no effect.
+/// - A resolved transaction can only be disputed again if the engine's config
+///   allows it. A charged back transaction can never be disputed again.
 /// - If a dispute would engage funds that are no longer available, nothing happens
 /// - If there is no record of transaction `tx`, nothing happens
-fn dispute(client: &mut Client, tx: u32, tx_hist: &mut TransactionLog) -> bool {
-    // check that the target transaction exists
-    match tx_hist.find(tx) {
-        Some(transaction) => {
-            if transaction.client != client.id() {
-                log::debug!(
-                    "dispute started by unauthorized client (offending client: {}",
-                    transaction.client
-                );
-                return false;
-            }
-            // make sure the transaction was issued by the client making the
-            // dispute request
-            if !transaction.under_dispute() {
-                // hold the client's funds
-                if let Err(_) = client.hold(transaction.amount) {
-                    log::debug!(
-                        "Inssuficient funds to dispute transaction {}",
-                        transaction.id
-                    );
-                    return false;
-                }
-                // mark transaction as under dispute
-                tx_hist.dispute(tx);
-            } else {
-                log::debug!("transaction {} is already under dispute", tx);
-                return false;
-            }
-        }
-        None => {
-            log::debug!("Invalid transaction number");
-            return false;
-        } // Invalid transaction number
+/// - Disputing a deposit holds the deposited funds out of the available
+///   balance. Disputing a withdrawal holds the withdrawn funds, which are no
+///   longer available, but would come back on a chargeback.
+/// - Disputing a transfer holds the transferred funds out of the available
+///   balance of the recipient. Only the client that sent them can dispute it.
+/// - The fee paid on the transaction is held out of the available balance of
+///   the house, and the client's funds are held net of it.
+/// - Funds are held in the asset of the disputed transaction.
+fn dispute<S: TxStore>(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    tx: u32,
+    target: Option<Transaction>,
+    tx_hist: &mut TransactionLog<S>,
+) -> Outcome {
+    let (transaction, next) =
+        find_disputable(config, client, tx, target, DisputeEvent::Dispute, tx_hist)?;
+    let (amount, fee) = (transaction.amount, transaction.fee());
+    if fee > Amount::ZERO {
+        house(config, others)?.update(transaction.asset, |b| b.hold(fee))?;
     }
+    match transaction.r#type {
+        // hold the transferred funds, until we know if they stay
+        Transfer => {
+            recipient_of(&transaction, others)?.update(transaction.asset, |b| b.hold(amount))
+        }
+        // hold the deposited funds, until we know if they stay
+        Deposit => client.update(transaction.asset, |b| b.hold(amount - fee)),
+        // hold the withdrawn funds, until we know if they come back
+        _ => {
+            let withdrawn = sum(amount, fee)?;
+            client.update(transaction.asset, |b| b.hold_reversal(withdrawn))
+        }
+    }?;
+    // mark transaction as under dispute
+    tx_hist.record(
+        transaction,
+        client.id(),
+        DisputeEvent::Dispute,
+        next,
+        Amount::ZERO,
+    );
     log::trace!(
         "opening dispute on transaction {} made by client {}",
         tx,
         client.id()
     );
-    true
+    Ok(Applied::Disputed)
 }
 
-/// Resolve a transaction
+/// Resolve a transaction, meaning the disputed transaction stands.
 /// A transaction can only be resolved by whoever issued it.
 /// If the transaction is not under dispute, it does nothing.
-fn resolve(client: &mut Client, tx: u32, tx_hist: &mut TransactionLog) -> bool {
-    match tx_hist.find(tx) {
-        Some(transaction) => {
-            // make sure the transaction was issued by the client making this request
-            if client.id() != transaction.client {
-                log::warn!("unauthorized client tried to resolve transaction {}", tx);
-                return false;
-            }
-            if transaction.under_dispute() {
-                if let Err(_) = client.release(transaction.amount) {
-                    log::warn!("Insufficient held funds to resolve transaction {}", tx);
-                    return false;
-                }
-                tx_hist.undispute(tx);
-            } else {
-                log::debug!(
-                    "transaction {} isn't under dispute. It cannot be resolved.",
-                    tx
-                );
-                return false;
-            }
+fn resolve<S: TxStore>(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    tx: u32,
+    target: Option<Transaction>,
+    tx_hist: &mut TransactionLog<S>,
+) -> Outcome {
+    let (transaction, next) =
+        find_disputable(config, client, tx, target, DisputeEvent::Resolve, tx_hist)?;
+    let (amount, fee) = (transaction.amount, transaction.fee());
+    // the house keeps the fee
+    if fee > Amount::ZERO {
+        house(config, others)?.update(transaction.asset, |b| b.release(fee))?;
+    }
+    match transaction.r#type {
+        // the transfer stands, the recipient can use the held funds again
+        Transfer => {
+            recipient_of(&transaction, others)?.update(transaction.asset, |b| b.release(amount))
         }
-        None => {
-            log::debug!("Invalid transaction number");
-            return false;
+        // the deposit stands, the held funds are available again
+        Deposit => client.update(transaction.asset, |b| b.release(amount - fee)),
+        // the withdrawal stands, the held funds leave the account again
+        _ => {
+            let withdrawn = sum(amount, fee)?;
+            client.update(transaction.asset, |b| b.confiscate(withdrawn))
         }
-    }
+    }?;
+    tx_hist.record(
+        transaction,
+        client.id(),
+        DisputeEvent::Resolve,
+        next,
+        Amount::ZERO,
+    );
     log::trace!(
         "resolving dispute on transaction {} made by client {}",
         tx,
         client.id()
     );
-    true
-}
-
-fn chargeback(client: &mut Client, tx: u32, tx_hist: &mut TransactionLog) -> bool {
-    match tx_hist.find(tx) {
-        Some(transaction) => {
-            // make sure the transaction was issued by the client making the
-            // dispute request
-            if transaction.under_dispute() {
-                if let Err(_) = client.confiscate(transaction.amount) {
-                    log::warn!("Inssuficient funds to chargeback transaction {}", tx);
-                    return false;
-                }
-                // Uncomment to allow a transaction to be disputed multiple times.
-                //tx_hist.resolve(tx);
-            } else {
-                log::debug!("Transaction {} is not under dispute", tx);
-                return false;
-                // invalid dispute order
-            }
-        }
-        None => {
-            log::debug!("Invalid transaction number");
-            return false;
+    Ok(Applied::Resolved)
+}
+
+/// Charge back a disputed transaction, reversing it, and lock the client's
+/// account.
+///
+/// A charged back transfer takes the held funds away from the recipient, and
+/// gives them back to the client that sent them. Like for any chargeback, it
+/// is the account of that client which gets locked.
+///
+/// The house gives back the fee paid on the transaction, and takes the fee of
+/// the chargeback out of what the client has left available.
+fn chargeback<S: TxStore>(
+    config: &Config,
+    client: &mut Client,
+    others: &mut [Client],
+    tx: u32,
+    target: Option<Transaction>,
+    tx_hist: &mut TransactionLog<S>,
+) -> Outcome {
+    let (transaction, next) = find_disputable(
+        config,
+        client,
+        tx,
+        target,
+        DisputeEvent::Chargeback,
+        tx_hist,
+    )?;
+    let (amount, fee) = (transaction.amount, transaction.fee());
+    let chargeback_fee = config.fees.of(&Chargeback, client.id(), amount);
+    if chargeback_fee > Amount::ZERO {
+        // the client cannot be left half charged back
+        house(config, others)?;
+    }
+    if fee > Amount::ZERO {
+        house(config, others)?.update(transaction.asset, |b| b.confiscate(fee))?;
+    }
+    // the client's balance is only written back once nothing can fail
+    let mut balance = client.balance(transaction.asset);
+    match transaction.r#type {
+        // the transfer is reversed, the held funds go back to the sender
+        Transfer => {
+            recipient_of(&transaction, others)?
+                .update(transaction.asset, |b| b.confiscate(amount))?;
+            balance.credit(amount)
         }
+        // the deposit is reversed, the held funds leave the account
+        Deposit => balance.confiscate(amount - fee),
+        // the withdrawal is reversed, the held funds are given back
+        _ => balance.release(sum(amount, fee)?),
+    }?;
+
+    let chargeback_fee = chargeback_fee.min(balance.available().max(Amount::ZERO));
+    if chargeback_fee > Amount::ZERO {
+        balance.debit(chargeback_fee)?;
+        house(config, others)?.update(transaction.asset, |b| b.credit(chargeback_fee))?;
     }
+    *client.balance_mut(transaction.asset) = balance;
+    tx_hist.record(
+        transaction,
+        client.id(),
+        DisputeEvent::Chargeback,
+        next,
+        chargeback_fee,
+    );
 
     client.lock();
     log::trace!(
@@ -189,5 +841,871 @@ fn chargeback(client: &mut Client, tx: u32, tx_hist: &mut TransactionLog) -> boo
         tx,
         client.id()
     );
-    true
+    Ok(Applied::ChargedBack)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asset::Asset;
+    use crate::fee::Fee;
+    use crate::risk::{Deposits, Limits, Withdrawn};
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn tx(r#type: Type, client: u16, id: u32, amount: &str) -> Transaction {
+        Transaction::new(r#type, client, id, amount.parse().expect("valid amount"))
+    }
+
+    /// A client whose deposit 1 of 10.0 got charged back
+    fn locked_client(tx_log: &mut TransactionLog) -> Client {
+        let config = Config::default();
+        let mut client = Client::with_id(1);
+        for t in &[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Deposit, 1, 2, "5.0"),
+            tx(Dispute, 1, 1, "0"),
+            tx(Chargeback, 1, 1, "0"),
+        ] {
+            execute_transaction(&config, t, tx_log, &mut client).expect("valid transaction");
+        }
+        assert!(client.is_locked());
+        client
+    }
+
+    #[test]
+    fn locked_rejects_all() {
+        let mut tx_log = TransactionLog::new();
+        let mut client = locked_client(&mut tx_log);
+        let config = Config {
+            lock_policy: LockPolicy::RejectAll,
+            ..Config::default()
+        };
+
+        for t in &[tx(Deposit, 1, 3, "1.0"), tx(Withdrawal, 1, 4, "1.0")] {
+            assert_eq!(
+                execute_transaction(&config, t, &mut tx_log, &mut client),
+                Err(Rejection::AccountLocked)
+            );
+        }
+        assert_eq!(client.total_balance(), "5.0".parse().unwrap());
+    }
+
+    #[test]
+    fn locked_settles_disputes() {
+        let mut tx_log = TransactionLog::new();
+        let mut client = Client::with_id(1);
+        let config = Config::default();
+        assert_eq!(config.lock_policy, LockPolicy::SettleDisputes);
+        // deposit 2 is still under dispute when deposit 1 is charged back
+        for t in &[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Deposit, 1, 2, "5.0"),
+            tx(Deposit, 1, 3, "1.0"),
+            tx(Dispute, 1, 1, "0"),
+            tx(Dispute, 1, 2, "0"),
+            tx(Chargeback, 1, 1, "0"),
+        ] {
+            execute_transaction(&config, t, &mut tx_log, &mut client).expect("valid transaction");
+        }
+        assert!(client.is_locked());
+        let reject_all = Config {
+            lock_policy: LockPolicy::RejectAll,
+            ..Config::default()
+        };
+        for config in &[&config, &reject_all] {
+            for t in &[
+                tx(Deposit, 1, 4, "1.0"),
+                tx(Withdrawal, 1, 5, "1.0"),
+                tx(Dispute, 1, 3, "0"),
+            ] {
+                assert_eq!(
+                    execute_transaction(config, t, &mut tx_log, &mut client),
+                    Err(Rejection::AccountLocked)
+                );
+            }
+        }
+        let resolve = tx(Resolve, 1, 2, "0");
+        assert_eq!(
+            execute_transaction(&reject_all, &resolve, &mut tx_log, &mut client),
+            Err(Rejection::AccountLocked)
+        );
+        assert_eq!(client.held_balance(), "5.0".parse().unwrap());
+        assert_eq!(
+            execute_transaction(&config, &resolve, &mut tx_log, &mut client),
+            Ok(Applied::Resolved)
+        );
+        assert_eq!(client.held_balance(), Amount::ZERO);
+        assert_eq!(client.available_balance(), "6.0".parse().unwrap());
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn lock_policy_from_str() {
+        assert_eq!("reject-all".parse().ok(), Some(LockPolicy::RejectAll));
+        assert_eq!(
+            "settle-disputes".parse().ok(),
+            Some(LockPolicy::SettleDisputes)
+        );
+        assert_eq!(
+            "allow-deposits".parse().ok(),
+            Some(LockPolicy::AllowDeposits)
+        );
+        assert_eq!(
+            "allow-disputes".parse().ok(),
+            Some(LockPolicy::AllowDisputes)
+        );
+        assert!("deposits".parse::<LockPolicy>().is_err());
+    }
+
+    #[test]
+    fn locked_allows_deposits() {
+        let mut tx_log = TransactionLog::new();
+        let mut client = locked_client(&mut tx_log);
+        let config = Config {
+            lock_policy: LockPolicy::AllowDeposits,
+            ..Config::default()
+        };
+
+        execute_transaction(&config, &tx(Deposit, 1, 3, "1.0"), &mut tx_log, &mut client)
+            .expect("deposits are allowed");
+        assert_eq!(
+            execute_transaction(
+                &config,
+                &tx(Withdrawal, 1, 4, "1.0"),
+                &mut tx_log,
+                &mut client
+            ),
+            Err(Rejection::AccountLocked)
+        );
+        assert_eq!(client.total_balance(), "6.0".parse().unwrap());
+    }
+
+    #[test]
+    fn locked_allows_disputes() {
+        let mut tx_log = TransactionLog::new();
+        let mut client = locked_client(&mut tx_log);
+        let config = Config {
+            lock_policy: LockPolicy::AllowDisputes,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            execute_transaction(&config, &tx(Deposit, 1, 3, "1.0"), &mut tx_log, &mut client),
+            Err(Rejection::AccountLocked)
+        );
+        execute_transaction(&config, &tx(Dispute, 1, 2, "0"), &mut tx_log, &mut client)
+            .expect("disputes are allowed");
+        assert_eq!(client.held_balance(), "5.0".parse().unwrap());
+    }
+
+    #[test]
+    fn rejections() {
+        let config = Config::default();
+        let mut tx_log = TransactionLog::new();
+        let mut alice = Client::with_id(1);
+        let mut bob = Client::with_id(2);
+
+        let mut exec = |t: Transaction, client: &mut Client| {
+            execute_transaction(&config, &t, &mut tx_log, client)
+        };
+        assert_eq!(
+            exec(tx(Deposit, 1, 1, "10.0"), &mut alice),
+            Ok(Applied::Deposited)
+        );
+        assert_eq!(
+            exec(tx(Deposit, 1, 1, "10.0"), &mut alice),
+            Err(Rejection::DuplicateTxId)
+        );
+        assert_eq!(
+            exec(tx(Withdrawal, 1, 2, "11.0"), &mut alice),
+            Err(Rejection::InsufficientFunds)
+        );
+        for t in &[
+            tx(Deposit, 1, 2, "-5.0"),
+            tx(Withdrawal, 1, 2, "-7.0"),
+            tx(Deposit, 1, 2, "0"),
+        ] {
+            assert_eq!(exec(t.clone(), &mut alice), Err(Rejection::InvalidAmount));
+        }
+        assert_eq!(alice.total_balance(), "10.0".parse().unwrap());
+        assert_eq!(
+            exec(tx(Dispute, 1, 3, "0"), &mut alice),
+            Err(Rejection::UnknownTx)
+        );
+        assert_eq!(
+            exec(tx(Dispute, 2, 1, "0"), &mut bob),
+            Err(Rejection::ClientMismatch)
+        );
+        assert_eq!(
+            exec(tx(Resolve, 1, 1, "0"), &mut alice),
+            Err(Rejection::NotDisputed)
+        );
+        assert_eq!(
+            exec(tx(Dispute, 1, 1, "0"), &mut alice),
+            Ok(Applied::Disputed)
+        );
+        assert_eq!(
+            exec(tx(Dispute, 1, 1, "0"), &mut alice),
+            Err(Rejection::AlreadyDisputed)
+        );
+        assert_eq!(
+            exec(tx(Chargeback, 2, 1, "0"), &mut bob),
+            Err(Rejection::ClientMismatch)
+        );
+        assert_eq!(
+            exec(tx(Chargeback, 1, 1, "0"), &mut alice),
+            Ok(Applied::ChargedBack)
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolved() {
+        let config = Config::default();
+        let mut tx_log = TransactionLog::new();
+        let mut client = Client::with_id(1);
+        for t in &[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Withdrawal, 1, 2, "4.0"),
+            tx(Dispute, 1, 2, "0"),
+        ] {
+            execute_transaction(&config, t, &mut tx_log, &mut client).unwrap();
+        }
+        assert_eq!(client.available_balance(), "6.0".parse().unwrap());
+        assert_eq!(client.held_balance(), "4.0".parse().unwrap());
+        assert_eq!(client.total_balance(), "10.0".parse().unwrap());
+
+        execute_transaction(&config, &tx(Resolve, 1, 2, "0"), &mut tx_log, &mut client).unwrap();
+        assert_eq!(client.available_balance(), "6.0".parse().unwrap());
+        assert_eq!(client.held_balance(), Amount::ZERO);
+        assert_eq!(client.total_balance(), "6.0".parse().unwrap());
+    }
+
+    #[test]
+    fn withdrawal_dispute_charged_back() {
+        let config = Config::default();
+        let mut tx_log = TransactionLog::new();
+        let mut client = Client::with_id(1);
+        for t in &[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Withdrawal, 1, 2, "10.0"),
+            tx(Dispute, 1, 2, "0"),
+            tx(Chargeback, 1, 2, "0"),
+        ] {
+            execute_transaction(&config, t, &mut tx_log, &mut client).unwrap();
+        }
+        assert_eq!(client.available_balance(), "10.0".parse().unwrap());
+        assert_eq!(client.held_balance(), Amount::ZERO);
+        assert_eq!(client.total_balance(), "10.0".parse().unwrap());
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn redispute() {
+        let mut tx_log = TransactionLog::new();
+        let mut client = Client::with_id(1);
+        let forbid = Config {
+            lock_policy: LockPolicy::AllowDisputes,
+            ..Config::default()
+        };
+        assert!(!forbid.allow_redispute);
+        for t in &[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Dispute, 1, 1, "0"),
+            tx(Resolve, 1, 1, "0"),
+        ] {
+            execute_transaction(&forbid, t, &mut tx_log, &mut client).unwrap();
+        }
+        assert_eq!(tx_log.find(1).unwrap().state(), DisputeState::Resolved);
+        assert_eq!(
+            execute_transaction(&forbid, &tx(Dispute, 1, 1, "0"), &mut tx_log, &mut client),
+            Err(Rejection::DisputeClosed)
+        );
+
+        let allow = Config {
+            allow_redispute: true,
+            ..forbid
+        };
+        for t in &[tx(Dispute, 1, 1, "0"), tx(Chargeback, 1, 1, "0")] {
+            execute_transaction(&allow, t, &mut tx_log, &mut client).unwrap();
+        }
+        assert_eq!(tx_log.find(1).unwrap().state(), DisputeState::ChargedBack);
+        // a charged back transaction is final
+        for (event, rejection) in &[
+            (Dispute, Rejection::DisputeClosed),
+            (Resolve, Rejection::NotDisputed),
+            (Chargeback, Rejection::NotDisputed),
+        ] {
+            assert_eq!(
+                execute_transaction(
+                    &allow,
+                    &tx(event.clone(), 1, 1, "0"),
+                    &mut tx_log,
+                    &mut client
+                ),
+                Err(*rejection)
+            );
+        }
+        assert_eq!(client.total_balance(), Amount::ZERO);
+    }
+
+    #[test]
+    fn dispute_window() {
+        let config = Config {
+            dispute_window: DisputeWindow::Transactions(1),
+            ..Config::default()
+        };
+        let mut tx_log = TransactionLog::with_window(config.dispute_window);
+        let mut client = Client::with_id(1);
+        let mut exec = |t: Transaction| execute_transaction(&config, &t, &mut tx_log, &mut client);
+
+        exec(tx(Deposit, 1, 1, "10.0")).unwrap();
+        exec(tx(Deposit, 1, 2, "10.0")).unwrap();
+        assert_eq!(
+            exec(tx(Dispute, 1, 1, "0")),
+            Err(Rejection::DisputeWindowExpired)
+        );
+        assert_eq!(
+            exec(tx(Deposit, 1, 1, "10.0")),
+            Err(Rejection::DuplicateTxId)
+        );
+        assert_eq!(exec(tx(Dispute, 1, 2, "0")), Ok(Applied::Disputed));
+    }
+
+    #[test]
+    fn unlock() {
+        let mut wallets = ClientWallets::new();
+        let mut tx_log = TransactionLog::new();
+        *wallets.get_or_create_mut(1) = locked_client(&mut tx_log);
+
+        assert!(wallets.unlock(1));
+        assert!(!wallets.unlock(2));
+        let client = wallets.get_or_create_mut(1);
+        execute_transaction(
+            &Config::default(),
+            &tx(Deposit, 1, 3, "1.0"),
+            &mut tx_log,
+            client,
+        )
+        .expect("client was unlocked");
+    }
+
+    #[test]
+    fn assets() {
+        let usd: Asset = "USD".parse().unwrap();
+        let mut engine = Engine::new();
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "10.0").with_asset(usd),
+            tx(Deposit, 1, 2, "5.0"),
+            tx(Withdrawal, 1, 3, "12.0").with_asset(usd),
+            // acts on the asset of the deposit, whatever its own
+            tx(Dispute, 1, 1, "0"),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(Applied::Deposited),
+                Ok(Applied::Deposited),
+                Err(Rejection::InsufficientFunds),
+                Ok(Applied::Disputed)
+            ]
+        );
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.balance(usd).held(), "10.0".parse().unwrap());
+        assert_eq!(client.available_balance(), "5.0".parse().unwrap());
+
+        engine.submit(&tx(Chargeback, 1, 1, "0")).unwrap();
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.balance(usd).total(), Amount::ZERO);
+        assert_eq!(client.total_balance(), "5.0".parse().unwrap());
+        assert!(client.is_locked());
+
+        // a rejected transaction leaves no balance behind
+        engine
+            .submit(&tx(Withdrawal, 2, 4, "1.0").with_asset(usd))
+            .unwrap_err();
+        assert!(engine.client(2).unwrap().balances().is_empty());
+    }
+
+    #[test]
+    fn transfers() {
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        let mut engine = Engine::with_config(Config {
+            allow_redispute: true,
+            ..Config::default()
+        });
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Transfer, 1, 2, "4.0").with_recipient(2),
+            tx(Transfer, 1, 3, "7.0").with_recipient(2),
+            tx(Transfer, 1, 4, "1.0").with_recipient(1),
+            tx(Transfer, 1, 5, "1.0"),
+            tx(Transfer, 1, 2, "1.0").with_recipient(2),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(Applied::Deposited),
+                Ok(Applied::Transferred),
+                Err(Rejection::InsufficientFunds),
+                Err(Rejection::InvalidRecipient),
+                Err(Rejection::InvalidRecipient),
+                Err(Rejection::DuplicateTxId)
+            ]
+        );
+        assert_eq!(engine.client(1).unwrap().available_balance(), amt("6.0"));
+        assert_eq!(engine.client(2).unwrap().available_balance(), amt("4.0"));
+        // a rejected transfer does not create the recipient's wallet
+        engine
+            .submit(
+                &tx(Transfer, 1, 6, "1.0")
+                    .with_recipient(3)
+                    .with_asset("BTC".parse().unwrap()),
+            )
+            .unwrap_err();
+        assert!(engine.client(3).is_none());
+
+        // the recipient cannot dispute the transfer, the sender can
+        assert_eq!(
+            engine.submit(&tx(Dispute, 2, 2, "0")),
+            Err(Rejection::ClientMismatch)
+        );
+        assert_eq!(
+            engine.submit(&tx(Dispute, 1, 2, "0")),
+            Ok(Applied::Disputed)
+        );
+        let recipient = engine.client(2).unwrap();
+        assert_eq!(recipient.held_balance(), amt("4.0"));
+        assert_eq!(recipient.available_balance(), Amount::ZERO);
+        engine.submit(&tx(Resolve, 1, 2, "0")).unwrap();
+        assert_eq!(engine.client(2).unwrap().available_balance(), amt("4.0"));
+
+        engine.submit(&tx(Dispute, 1, 2, "0")).unwrap();
+        assert_eq!(
+            engine.submit(&tx(Chargeback, 1, 2, "0")),
+            Ok(Applied::ChargedBack)
+        );
+        let (sender, recipient) = (engine.client(1).unwrap(), engine.client(2).unwrap());
+        assert_eq!(sender.total_balance(), amt("10.0"));
+        assert!(sender.is_locked());
+        assert_eq!(recipient.total_balance(), Amount::ZERO);
+        assert!(!recipient.is_locked());
+    }
+
+    #[test]
+    fn transfer_to_locked_account() {
+        let mut engine = Engine::new();
+        engine
+            .submit_batch(&[
+                tx(Deposit, 1, 1, "10.0"),
+                tx(Deposit, 2, 2, "5.0"),
+                tx(Dispute, 2, 2, "0"),
+                tx(Chargeback, 2, 2, "0"),
+            ])
+            .into_iter()
+            .for_each(|outcome| assert!(outcome.is_ok()));
+        let transfer = tx(Transfer, 1, 3, "1.0").with_recipient(2);
+        assert_eq!(engine.submit(&transfer), Err(Rejection::RecipientLocked));
+        assert_eq!(
+            engine.submit(&transfer.clone().with_recipient(1)),
+            Err(Rejection::InvalidRecipient)
+        );
+        // a locked sender is refused first, whatever the recipient
+        assert_eq!(
+            engine.submit(&tx(Transfer, 2, 4, "0.0").with_recipient(1)),
+            Err(Rejection::AccountLocked)
+        );
+
+        let config = Config {
+            lock_policy: LockPolicy::AllowDeposits,
+            ..Config::default()
+        };
+        let (wallets, tx_log) = engine.into_parts();
+        let mut engine = Engine::from_parts(config, wallets, tx_log);
+        assert_eq!(engine.submit(&transfer), Ok(Applied::Transferred));
+        assert_eq!(
+            engine.client(2).unwrap().available_balance(),
+            "1.0".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn spent_transfer_dispute() {
+        let mut engine = Engine::new();
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Transfer, 1, 2, "10.0").with_recipient(2),
+            tx(Withdrawal, 2, 3, "8.0"),
+            // the recipient no longer has the funds
+            tx(Dispute, 1, 2, "0"),
+        ]);
+        assert_eq!(outcomes[3], Err(Rejection::InsufficientFunds));
+        assert!(!engine.transaction(2).unwrap().under_dispute());
+
+        // a single wallet is not enough to execute a transfer
+        let mut tx_log = TransactionLog::new();
+        let mut client = Client::with_id(1);
+        assert_eq!(
+            execute_transaction(
+                &Config::default(),
+                &tx(Transfer, 1, 1, "1.0").with_recipient(2),
+                &mut tx_log,
+                &mut client
+            ),
+            Err(Rejection::InvalidRecipient)
+        );
+    }
+
+    #[test]
+    fn fees() {
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        let config = Config {
+            fees: Fees {
+                deposit: Fee::Flat(amt("1.0")),
+                withdrawal: Fee::Percent(amt("10")),
+                chargeback: Fee::Flat(amt("2.0")),
+                house: 100,
+            },
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(config.clone());
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "10.0"),
+            tx(Withdrawal, 1, 2, "5.0"),
+            tx(Withdrawal, 1, 3, "4.0"),
+            tx(Dispute, 1, 2, "0"),
+        ]);
+        assert_eq!(outcomes[2], Err(Rejection::InsufficientFunds));
+        assert_eq!(engine.transaction(2).unwrap().fee(), amt("0.5"));
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available_balance(), amt("3.5"));
+        // the fee would come back along with the withdrawn funds
+        assert_eq!(client.held_balance(), amt("5.5"));
+        let house = engine.client(100).unwrap();
+        assert_eq!(house.available_balance(), amt("1.0"));
+        assert_eq!(house.held_balance(), amt("0.5"));
+
+        engine.submit(&tx(Resolve, 1, 2, "0")).unwrap();
+        assert_eq!(engine.client(1).unwrap().total_balance(), amt("3.5"));
+        assert_eq!(engine.client(100).unwrap().available_balance(), amt("1.5"));
+
+        // the deposit's funds, net of the fee, are no longer available
+        assert_eq!(
+            engine.submit(&tx(Dispute, 1, 1, "0")),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(engine.client(100).unwrap().held_balance(), Amount::ZERO);
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 4, "10.0"),
+            tx(Dispute, 1, 1, "0"),
+            tx(Chargeback, 1, 1, "0"),
+        ]);
+        assert!(outcomes.iter().all(Result::is_ok));
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available_balance(), amt("1.5"));
+        assert_eq!(client.held_balance(), Amount::ZERO);
+        assert!(client.is_locked());
+        // gave back the fee of the deposit, took the one of the chargeback
+        let house = engine.client(100).unwrap();
+        assert_eq!(house.total_balance(), amt("3.5"));
+        assert_eq!(house.held_balance(), Amount::ZERO);
+        assert_eq!(engine.tx_log().history(1)[1].fee, amt("2.0"));
+
+        let outcomes = engine.submit_batch(&[
+            // the house pays no fee
+            tx(Deposit, 100, 5, "5.0"),
+            // a deposit pays at most what it brings
+            tx(Deposit, 2, 6, "0.5"),
+        ]);
+        assert!(outcomes.iter().all(Result::is_ok));
+        assert_eq!(engine.client(100).unwrap().total_balance(), amt("9.0"));
+        assert_eq!(engine.client(2).unwrap().total_balance(), Amount::ZERO);
+
+        let mut client = Client::with_id(1);
+        assert_eq!(
+            execute_transaction(
+                &config,
+                &tx(Deposit, 1, 1, "1.0"),
+                &mut TransactionLog::new(),
+                &mut client
+            ),
+            Err(Rejection::NoHouseAccount)
+        );
+        assert!(client.balances().is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        let config = Config {
+            risk: RiskRules {
+                global: Limits {
+                    max_withdrawal: Some(amt("5.0")),
+                    withdrawn: Some(Withdrawn {
+                        total: amt("8.0"),
+                        ticks: 4,
+                    }),
+                    deposits: Some(Deposits { count: 2, ticks: 4 }),
+                    max_balance: Some(amt("20.0")),
+                },
+                clients: vec![(
+                    2,
+                    Limits {
+                        max_balance: Some(amt("100.0")),
+                        ..Limits::default()
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+            fees: Fees {
+                deposit: Fee::Flat(amt("1.0")),
+                house: 100,
+                ..Fees::default()
+            },
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(config);
+        engine.submit(&tx(Deposit, 100, 1, "1.0")).unwrap();
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 2, "12.0"),
+            tx(Deposit, 1, 3, "11.0"),
+            // counted net of the fee
+            tx(Deposit, 1, 4, "10.0"),
+            tx(Deposit, 1, 5, "1.0"),
+            tx(Withdrawal, 1, 6, "6.0"),
+            tx(Withdrawal, 1, 7, "5.0"),
+            tx(Withdrawal, 1, 8, "4.0"),
+            tx(Withdrawal, 1, 9, "3.0"),
+            tx(Deposit, 1, 10, "1.0"),
+            tx(Withdrawal, 1, 11, "3.0"),
+            tx(Deposit, 2, 12, "50.0"),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(Applied::Deposited),
+                Err(Rejection::LimitExceeded(Limit::MaxBalance)),
+                Ok(Applied::Deposited),
+                Err(Rejection::LimitExceeded(Limit::Deposits)),
+                Err(Rejection::LimitExceeded(Limit::MaxWithdrawal)),
+                Ok(Applied::Withdrew),
+                Err(Rejection::LimitExceeded(Limit::Withdrawn)),
+                Ok(Applied::Withdrew),
+                Ok(Applied::Deposited),
+                // the first withdrawal left the window
+                Ok(Applied::Withdrew),
+                Ok(Applied::Deposited),
+            ]
+        );
+        assert!(engine.transaction(3).is_none());
+        assert_eq!(engine.client(1).unwrap().total_balance(), amt("9.0"));
+        assert_eq!(engine.client(2).unwrap().total_balance(), amt("49.0"));
+    }
+
+    /// A transfer is a withdrawal for the client, and a deposit for the
+    /// recipient
+    #[test]
+    fn transfer_limits() {
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        let config = Config {
+            risk: RiskRules {
+                global: Limits {
+                    max_withdrawal: Some(amt("5.0")),
+                    withdrawn: Some(Withdrawn {
+                        total: amt("8.0"),
+                        ticks: 100,
+                    }),
+                    ..Limits::default()
+                },
+                clients: vec![(
+                    2,
+                    Limits {
+                        deposits: Some(Deposits {
+                            count: 2,
+                            ticks: 100,
+                        }),
+                        max_balance: Some(amt("9.0")),
+                        ..Limits::default()
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(config);
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "50.0"),
+            tx(Deposit, 3, 2, "50.0"),
+            tx(Transfer, 1, 3, "6.0").with_recipient(2),
+            tx(Transfer, 1, 4, "5.0").with_recipient(2),
+            tx(Transfer, 1, 5, "4.0").with_recipient(3),
+            // the transfer counts in the amount withdrawn
+            tx(Withdrawal, 1, 6, "4.0"),
+            tx(Transfer, 3, 7, "5.0").with_recipient(2),
+            tx(Deposit, 2, 8, "1.0"),
+            // the transfer counts in the number of deposits
+            tx(Transfer, 3, 9, "1.0").with_recipient(2),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(Applied::Deposited),
+                Ok(Applied::Deposited),
+                Err(Rejection::LimitExceeded(Limit::MaxWithdrawal)),
+                Ok(Applied::Transferred),
+                Err(Rejection::LimitExceeded(Limit::Withdrawn)),
+                Err(Rejection::LimitExceeded(Limit::Withdrawn)),
+                Err(Rejection::LimitExceeded(Limit::MaxBalance)),
+                Ok(Applied::Deposited),
+                Err(Rejection::LimitExceeded(Limit::Deposits)),
+            ]
+        );
+        assert_eq!(engine.client(1).unwrap().total_balance(), amt("45.0"));
+        assert_eq!(engine.client(2).unwrap().total_balance(), amt("6.0"));
+        assert_eq!(engine.client(3).unwrap().total_balance(), amt("50.0"));
+    }
+
+    /// A store counting how many times transactions are read from it
+    struct CountingStore {
+        store: DenseTxStore,
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl TxStore for CountingStore {
+        fn get(&self, tx_id: u32) -> Option<Transaction> {
+            self.reads.set(self.reads.get() + 1);
+            self.store.get(tx_id)
+        }
+
+        fn put(&mut self, t: &Transaction) {
+            self.store.put(t)
+        }
+
+        fn remove(&mut self, tx_id: u32) {
+            self.store.remove(tx_id)
+        }
+
+        fn contains(&self, tx_id: u32) -> bool {
+            self.reads.set(self.reads.get() + 1);
+            self.store.contains(tx_id)
+        }
+
+        fn len(&self) -> usize {
+            self.store.len()
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = Transaction> + '_> {
+            self.store.transactions()
+        }
+    }
+
+    /// Dispute events, transfers included, read the transaction they target
+    /// from the store once
+    #[test]
+    fn dispute_reads_once() {
+        let reads = Rc::new(Cell::new(0));
+        let store = CountingStore {
+            store: DenseTxStore::new(),
+            reads: Rc::clone(&reads),
+        };
+        let tx_log = TransactionLog::with_store(store, DisputeWindow::Unbounded);
+        let mut engine = Engine::from_parts(Config::default(), ClientWallets::new(), tx_log);
+        engine.submit(&tx(Deposit, 1, 1, "10.0")).unwrap();
+        engine
+            .submit(&tx(Transfer, 1, 2, "4.0").with_recipient(2))
+            .unwrap();
+        for t in [
+            tx(Dispute, 1, 1, "0"),
+            tx(Resolve, 1, 1, "0"),
+            tx(Dispute, 1, 3, "0"),
+            tx(Dispute, 1, 2, "0"),
+            tx(Chargeback, 1, 2, "0"),
+        ] {
+            reads.set(0);
+            let _ = engine.submit(&t);
+            assert_eq!(reads.get(), 1);
+        }
+        assert_eq!(engine.client(2).unwrap().total_balance(), Amount::ZERO);
+    }
+
+    #[test]
+    fn input_state_is_ignored() {
+        let mut engine = Engine::new();
+        let disputed = tx(Deposit, 1, 1, "10.0").with_state(DisputeState::Disputed);
+        let outcomes = engine.submit_batch(&[disputed, tx(Chargeback, 1, 1, "0")]);
+        assert_eq!(outcomes[1], Err(Rejection::NotDisputed));
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.total_balance(), "10.0".parse().unwrap());
+        assert!(!client.is_locked());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut engine = Engine::new();
+        let outcomes = engine.submit_batch(&[
+            tx(Deposit, 1, 1, "900000000000000"),
+            tx(Deposit, 1, 2, "900000000000000"),
+            tx(Deposit, 2, 3, "100000000000000"),
+            tx(Transfer, 2, 4, "100000000000000").with_recipient(1),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(Applied::Deposited),
+                Err(Rejection::AmountOverflow),
+                Ok(Applied::Deposited),
+                Err(Rejection::AmountOverflow),
+            ]
+        );
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        assert_eq!(
+            engine.client(1).unwrap().total_balance(),
+            amt("900000000000000")
+        );
+        // the sender keeps their funds
+        assert_eq!(
+            engine.client(2).unwrap().total_balance(),
+            amt("100000000000000")
+        );
+    }
+
+    #[test]
+    fn incremental() {
+        let mut engine = Engine::new();
+        assert!(engine.client(1).is_none());
+
+        assert_eq!(
+            engine.submit(&tx(Deposit, 1, 1, "10.0")),
+            Ok(Applied::Deposited)
+        );
+        assert_eq!(
+            engine.client(1).unwrap().available_balance(),
+            "10.0".parse().unwrap()
+        );
+        let outcomes = engine.submit_batch(&[
+            tx(Withdrawal, 2, 2, "1.0"),
+            tx(Deposit, 2, 3, "2.0"),
+            tx(Dispute, 1, 1, "0"),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                Err(Rejection::InsufficientFunds),
+                Ok(Applied::Deposited),
+                Ok(Applied::Disputed)
+            ]
+        );
+        assert!(engine.transaction(1).unwrap().under_dispute());
+        assert!(engine.transaction(2).is_none());
+
+        let balances: Vec<_> = engine.balances().iter().map(|c| c.id()).collect();
+        assert_eq!(balances, vec![1, 2]);
+        assert_eq!(
+            engine.client(1).unwrap().held_balance(),
+            "10.0".parse().unwrap()
+        );
+    }
 }
@@ -7,12 +7,47 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("Could not read CSV ({0})")]
     ParseError(#[from] csv::Error),
+    #[error("Invalid amount `{0}`")]
+    InvalidAmount(String),
+    #[error("Invalid asset `{0}`")]
+    InvalidAsset(String),
+    #[error("Invalid fee `{0}`")]
+    InvalidFee(String),
+    #[error("Invalid limits ({0})")]
+    InvalidLimits(serde_json::Error),
+    #[error("Invalid generator configuration ({0})")]
+    InvalidGenerator(&'static str),
     #[error("Client does not have sufficient funds available")]
     InssuficientFunds,
+    #[error("Amount out of range")]
+    AmountOverflow,
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("Transaction store failed ({0})")]
+    TxStore(std::io::Error),
+    #[error("Invalid input row ({0})")]
+    InvalidRow(#[from] crate::parser::ParseIssue),
     #[error("Could not deserialize input")]
     DeserializeError,
+    #[error("Could not write JSON ({0})")]
+    Json(#[from] serde_json::Error),
+    #[error("Unknown output format `{0}`")]
+    UnknownFormat(String),
+    #[error("Unknown lock policy `{0}`")]
+    UnknownLockPolicy(String),
     #[error("Could not serialize output")]
     SerializeError,
+    #[error("Unsupported snapshot version {0}")]
+    SnapshotVersion(u32),
+    #[error(
+        "Snapshot has dispute window {saved:?}, but the engine is configured with {configured:?}"
+    )]
+    WindowMismatch {
+        saved: crate::transaction::DisputeWindow,
+        configured: crate::transaction::DisputeWindow,
+    },
+    #[error("The parallel engine does not support {0}")]
+    Unsupported(&'static str),
+    #[error("A worker thread of the parallel engine died")]
+    WorkerDied,
 }
@@ -0,0 +1,190 @@
+//! Fees the engine takes on transactions, for the house
+use crate::amount::{Amount, DECIMALS};
+use crate::error::{Error, Result};
+use crate::transaction::Type;
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// How much a transaction costs, given its amount
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Fee {
+    #[default]
+    Free,
+    /// The same fee whatever the amount
+    Flat(Amount),
+    /// A percentage of the amount, rounded down to the unit: `Percent(1.5)`
+    /// takes 1.5%
+    Percent(Amount),
+    /// Tiers made of the smallest amount they apply to, and of their fee. An
+    /// amount pays the fee of the highest tier it reaches, and nothing if it
+    /// reaches none.
+    Tiered(Vec<(Amount, Fee)>),
+}
+
+impl Fee {
+    /// The fee on `amount`, never below zero
+    pub fn of(&self, amount: Amount) -> Amount {
+        let fee = match self {
+            Fee::Free => Amount::ZERO,
+            Fee::Flat(fee) => *fee,
+            Fee::Percent(rate) => {
+                let units =
+                    amount.units() as i128 * rate.units() as i128 / (100 * 10_i128.pow(DECIMALS));
+                Amount::from_units(i64::try_from(units).unwrap_or(i64::MAX))
+            }
+            Fee::Tiered(tiers) => tiers
+                .iter()
+                .filter(|(from, _)| *from <= amount)
+                .max_by_key(|(from, _)| *from)
+                .map_or(Amount::ZERO, |(_, fee)| fee.of(amount)),
+        };
+        fee.max(Amount::ZERO)
+    }
+
+    /// Checks if the fee is always zero
+    pub fn is_free(&self) -> bool {
+        match self {
+            Fee::Free => true,
+            Fee::Flat(fee) | Fee::Percent(fee) => *fee <= Amount::ZERO,
+            Fee::Tiered(tiers) => tiers.iter().all(|(_, fee)| fee.is_free()),
+        }
+    }
+}
+
+impl FromStr for Fee {
+    type Err = Error;
+
+    /// Parse a flat fee (`0.5`), a percentage (`1.5%`), or tiers of either,
+    /// separated by commas, each preceded by the amount it starts at
+    /// (`0:1.0,100:0.5%`).
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFee(s.to_string());
+        let single = |fee: &str| -> Result<Fee> {
+            let fee = fee.trim();
+            match fee.strip_suffix('%') {
+                Some(rate) => rate.trim().parse().map(Fee::Percent),
+                None => fee.parse().map(Fee::Flat),
+            }
+            .map_err(|_| invalid())
+        };
+        if !s.contains(':') {
+            return single(s);
+        }
+        s.split(',')
+            .map(|tier| {
+                let (from, fee) = tier.split_once(':').ok_or_else(invalid)?;
+                let from = from.trim().parse().map_err(|_| invalid())?;
+                Ok((from, single(fee)?))
+            })
+            .collect::<Result<_>>()
+            .map(Fee::Tiered)
+    }
+}
+
+/// The fees of every type of transaction, and where they go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fees {
+    /// Taken out of deposits, at most the amount deposited
+    pub deposit: Fee,
+    /// Taken on top of the amount withdrawn
+    pub withdrawal: Fee,
+    /// Taken on the amount of a charged back transaction, out of what is
+    /// left available to the client
+    pub chargeback: Fee,
+    /// The client whose wallet collects the fees. Its own transactions are
+    /// free.
+    pub house: u16,
+}
+
+impl Default for Fees {
+    fn default() -> Self {
+        Fees {
+            deposit: Fee::Free,
+            withdrawal: Fee::Free,
+            chargeback: Fee::Free,
+            house: u16::MAX,
+        }
+    }
+}
+
+impl Fees {
+    /// The fee client `client` pays on a transaction of type `ty`, of
+    /// `amount`
+    pub fn of(&self, ty: &Type, client: u16, amount: Amount) -> Amount {
+        if client == self.house {
+            return Amount::ZERO;
+        }
+        match ty {
+            Type::Deposit => self.deposit.of(amount),
+            Type::Withdrawal => self.withdrawal.of(amount),
+            Type::Chargeback => self.chargeback.of(amount),
+            _ => Amount::ZERO,
+        }
+    }
+
+    /// Checks if no transaction ever pays a fee
+    pub fn is_free(&self) -> bool {
+        self.deposit.is_free() && self.withdrawal.is_free() && self.chargeback.is_free()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().expect("valid amount")
+    }
+
+    #[test]
+    fn fees() {
+        assert_eq!(Fee::Free.of(amt("10")), Amount::ZERO);
+        assert_eq!(Fee::Flat(amt("0.5")).of(amt("10")), amt("0.5"));
+        assert_eq!(Fee::Percent(amt("1.5")).of(amt("10")), amt("0.15"));
+        // rounded down to the unit
+        assert_eq!(Fee::Percent(amt("1")).of(amt("0.0199")), amt("0.0001"));
+        assert_eq!(Fee::Percent(amt("1")).of(amt("-10")), Amount::ZERO);
+
+        let tiered = Fee::Tiered(vec![
+            (amt("100"), Fee::Percent(amt("1"))),
+            (amt("10"), Fee::Flat(amt("2"))),
+        ]);
+        assert_eq!(tiered.of(amt("5")), Amount::ZERO);
+        assert_eq!(tiered.of(amt("10")), amt("2"));
+        assert_eq!(tiered.of(amt("99.9999")), amt("2"));
+        assert_eq!(tiered.of(amt("1000")), amt("10"));
+        assert!(!tiered.is_free());
+        assert!(Fee::Tiered(vec![(amt("1"), Fee::Free)]).is_free());
+
+        let fees = Fees {
+            deposit: Fee::Flat(amt("1")),
+            house: 7,
+            ..Fees::default()
+        };
+        assert_eq!(fees.of(&Type::Deposit, 1, amt("10")), amt("1"));
+        assert_eq!(fees.of(&Type::Deposit, 7, amt("10")), Amount::ZERO);
+        assert_eq!(fees.of(&Type::Withdrawal, 1, amt("10")), Amount::ZERO);
+        assert!(!fees.is_free());
+        assert!(Fees::default().is_free());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("0.5".parse::<Fee>().unwrap(), Fee::Flat(amt("0.5")));
+        assert_eq!(" 1.5 %".parse::<Fee>().unwrap(), Fee::Percent(amt("1.5")));
+        assert_eq!(
+            "0:1.0, 100:0.5%".parse::<Fee>().unwrap(),
+            Fee::Tiered(vec![
+                (Amount::ZERO, Fee::Flat(amt("1"))),
+                (amt("100"), Fee::Percent(amt("0.5")))
+            ])
+        );
+        for invalid in &["", "%", "1.5%%", "abc", "0:1,2", "0:1:2", "x:1"] {
+            match invalid.parse::<Fee>() {
+                Err(Error::InvalidFee(s)) => assert_eq!(&s, invalid),
+                otherwise => panic!("{:?}", otherwise),
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Write-ahead journal of the engine, to recover its state after a crash
+//!
+//! Every row that changed the engine's state is appended to the journal, one
+//! JSON object per line, along with where it was read from. The journal is
+//! synced to disk in batches.
+//! Every so often, the whole state of the engine is written to a snapshot file
+//! next to the journal, and the journal starts over.
+//!
+//! A snapshot costs as much as the transaction log is long, so snapshots get
+//! further apart as the log grows: the next one is only due once the journal
+//! is at least as long as the log was at the last one. Snapshots then cost
+//! at most as much as journaling, rather than growing with the square of the
+//! number of rows, and replaying the journal never takes longer than loading
+//! the snapshot.
+//!
+//! A restarted engine loads the snapshot, replays the journal on top of it,
+//! and resumes reading its input right after the last journaled row.
+use crate::client::ClientWallets;
+use crate::engine::{self, Config};
+use crate::error::Result;
+use crate::snapshot::{self, Snapshot};
+use crate::storage::TxStore;
+use crate::transaction::{Transaction, TransactionLog};
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of rows written to the journal before it is synced to disk
+const SYNC_BATCH: usize = 1024;
+/// Least number of rows written to the journal before a snapshot is taken
+const SNAPSHOT_EVERY: u64 = 100_000;
+
+/// Where a row was read from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Offset {
+    /// Index of the input, when reading several of them
+    pub input: usize,
+    pub line: u64,
+    pub byte: u64,
+}
+
+impl Offset {
+    /// Checks if the row starting on `line` of `input` comes before this
+    /// offset, or is the row at this offset
+    pub fn covers(&self, input: usize, line: u64) -> bool {
+        input < self.input || (input == self.input && line <= self.line)
+    }
+}
+
+/// A row of the journal
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    /// Number of the entry, counting from the first run of the journal
+    seq: u64,
+    /// Logical clock of the transaction log once the row was processed
+    tick: u64,
+    offset: Offset,
+    /// The row as read. The dispute state a transaction comes with is not
+    /// journaled, the engine does not trust it anyway.
+    transaction: Transaction,
+}
+
+/// The whole state of the engine, once the journal reached `seq`
+#[derive(Serialize, Deserialize, Debug)]
+struct Checkpoint {
+    seq: u64,
+    offset: Offset,
+    engine: Snapshot,
+}
+
+/// The state of the engine, as rebuilt from a journal
+pub struct Recovered<S: TxStore> {
+    pub wallets: ClientWallets,
+    pub tx_log: TransactionLog<S>,
+    /// Offset of the last row that made it to the journal. Input up to, and
+    /// including, this row was already processed.
+    pub resume: Option<Offset>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// number of the last entry
+    seq: u64,
+    /// offset of the last entry
+    offset: Option<Offset>,
+    /// entries not synced to disk yet
+    unsynced: usize,
+    /// entries written since the last snapshot
+    since_snapshot: u64,
+    snapshot_every: u64,
+    /// length of the transaction log at the last snapshot
+    snapshot_len: u64,
+}
+
+impl Journal {
+    /// Open the journal at `path`, and rebuild the state of the engine from
+    /// it and from its snapshot.
+    /// If there is no journal yet, the engine starts from an empty state.
+    ///
+    /// Transactions of the rebuilt log are kept in `store`.
+    pub fn recover<S: TxStore>(
+        path: &Path,
+        config: &Config,
+        store: S,
+    ) -> Result<(Journal, Recovered<S>)> {
+        let mut seq = 0;
+        let mut resume = None;
+        let mut snapshot_len = 0;
+        let (mut wallets, mut tx_log) = match fs::read(snapshot_path(path)) {
+            Ok(bytes) => {
+                let checkpoint: Checkpoint = serde_json::from_slice(&bytes)?;
+                checkpoint.engine.check_version()?;
+                seq = checkpoint.seq;
+                resume = Some(checkpoint.offset);
+                let restored = checkpoint.engine.restore(store, config.dispute_window)?;
+                snapshot_len = restored.1.len() as u64;
+                restored
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+                ClientWallets::new(),
+                TransactionLog::with_store(store, config.dispute_window),
+            ),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        // replay the journal, up to the last complete entry
+        let mut valid = 0;
+        let mut since_snapshot = 0;
+        for line in BufReader::new(&file).split(b'\n') {
+            let line = line?;
+            let entry: Entry = match serde_json::from_slice(&line) {
+                Ok(entry) => entry,
+                // torn write, the process died while writing this entry
+                Err(_) => break,
+            };
+            valid += line.len() as u64 + 1;
+            since_snapshot += 1;
+            // the snapshot already covers this entry
+            if entry.seq <= seq {
+                continue;
+            }
+            tx_log.advance_to(entry.tick - 1);
+            let _ = engine::process(config, &entry.transaction, &mut wallets, &mut tx_log);
+            seq = entry.seq;
+            resume = Some(entry.offset);
+        }
+        file.set_len(valid)?;
+        log::info!("recovered {} journal entries from {}", seq, path.display());
+
+        let journal = Journal {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            seq,
+            offset: resume,
+            unsynced: 0,
+            since_snapshot,
+            snapshot_every: SNAPSHOT_EVERY,
+            snapshot_len,
+        };
+        Ok((
+            journal,
+            Recovered {
+                wallets,
+                tx_log,
+                resume,
+            },
+        ))
+    }
+
+    /// Take a snapshot every `n` journal entries, or more if the transaction
+    /// log is longer than that
+    pub fn with_snapshot_every(mut self, n: u64) -> Self {
+        self.snapshot_every = n.max(1);
+        self
+    }
+
+    /// Append a row to the journal.
+    ///
+    /// `t` was read at `offset`, and processed by an engine whose transaction
+    /// log reached `tick`. Rows that were rejected without creating a wallet
+    /// do not need to be journaled.
+    pub fn append(&mut self, t: &Transaction, offset: Offset, tick: u64) -> Result<()> {
+        self.seq += 1;
+        let entry = Entry {
+            seq: self.seq,
+            tick,
+            offset,
+            transaction: t.clone(),
+        };
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+        self.offset = Some(offset);
+        self.since_snapshot += 1;
+        self.unsynced += 1;
+        if self.unsynced >= SYNC_BATCH {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Checks if enough entries were written since the last snapshot to take
+    /// another one: the configured number, and no less than the length of the
+    /// transaction log at the last snapshot
+    pub fn snapshot_due(&self) -> bool {
+        self.since_snapshot >= self.snapshot_every.max(self.snapshot_len)
+    }
+
+    /// Make sure every entry made it to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Save the whole state of the engine, and start the journal over.
+    ///
+    /// The snapshot is written next to the journal, and replaces the previous
+    /// one only once complete. It takes time and space in proportion to the
+    /// number of wallets and of logged transactions.
+    pub fn snapshot<S: TxStore>(
+        &mut self,
+        wallets: &ClientWallets,
+        tx_log: &TransactionLog<S>,
+    ) -> Result<()> {
+        self.sync()?;
+        let checkpoint = Checkpoint {
+            seq: self.seq,
+            offset: self.offset.unwrap_or_default(),
+            engine: Snapshot::take(wallets, tx_log),
+        };
+        tx_log.check_store()?;
+        snapshot::save_json(&snapshot_path(&self.path), &checkpoint)?;
+        // entries up to `seq` are in the snapshot now. If we die before the
+        // journal is emptied, they are skipped on recovery.
+        self.writer.get_ref().set_len(0)?;
+        self.writer.get_ref().sync_all()?;
+        self.since_snapshot = 0;
+        self.snapshot_len = tx_log.len() as u64;
+        log::info!("snapshot of journal entry {} taken", self.seq);
+        Ok(())
+    }
+}
+
+/// The snapshot of the journal at `path` sits next to it
+fn snapshot_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".snapshot");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::transaction::utils::{GeneratorConfig, RandomTransactions};
+    use crate::transaction::DisputeState;
+    use std::collections::HashMap;
+
+    fn balances(wallets: &ClientWallets) -> Vec<String> {
+        wallets
+            .clients()
+            .iter()
+            .map(|c| serde_json::to_string(c).unwrap())
+            .collect()
+    }
+
+    /// The dispute state of every transaction `rows` reference
+    fn states(
+        rows: &[Transaction],
+        tx_log: &TransactionLog<HashMap<u32, Transaction>>,
+    ) -> Vec<Option<DisputeState>> {
+        rows.iter()
+            .map(|t| tx_log.find(t.id).map(|t| t.state()))
+            .collect()
+    }
+
+    /// Kill the engine at every point of a stream, and check that the
+    /// recovered engine ends up where an engine that never died would be.
+    #[test]
+    fn crash_and_recover() {
+        let config = Config::default();
+        // few clients and ids, so that most disputes hit a logged transaction
+        let generator = GeneratorConfig {
+            clients: 1..=20,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            new_id_probability: 0.5,
+            id_spread: 64,
+            ..GeneratorConfig::default()
+        };
+        let rows: Vec<_> = RandomTransactions::with_config(generator, 7)
+            .unwrap()
+            .take(3_000)
+            .collect();
+        let mut expected = (
+            ClientWallets::new(),
+            TransactionLog::with_store(HashMap::new(), config.dispute_window),
+        );
+        for t in &rows {
+            let _ = engine::process(&config, t, &mut expected.0, &mut expected.1);
+        }
+        let expected_states = states(&rows, &expected.1);
+        for state in &[
+            DisputeState::Disputed,
+            DisputeState::Resolved,
+            DisputeState::ChargedBack,
+        ] {
+            assert!(expected_states.contains(&Some(*state)), "{:?}", state);
+        }
+
+        let path = std::env::temp_dir().join(format!("pay-engine-journal-{}", std::process::id()));
+        for crash_at in &[0, 1, 999, 2_000, 3_000] {
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(snapshot_path(&path));
+
+            // first run, dies after `crash_at` rows
+            let (journal, state) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+            let mut journal = journal.with_snapshot_every(500);
+            let (mut wallets, mut tx_log) = (state.wallets, state.tx_log);
+            for (i, t) in rows.iter().enumerate().take(*crash_at) {
+                let new_client = wallets.get(t.client).is_none();
+                let outcome = engine::process(&config, t, &mut wallets, &mut tx_log);
+                if outcome.is_ok() || new_client {
+                    let offset = Offset {
+                        input: 0,
+                        line: i as u64 + 1,
+                        byte: 0,
+                    };
+                    journal.append(t, offset, tx_log.now()).unwrap();
+                }
+                if journal.snapshot_due() {
+                    journal.snapshot(&wallets, &tx_log).unwrap();
+                }
+            }
+            journal.sync().unwrap();
+            drop(journal);
+
+            // second run, picks up where the first one stopped
+            let (_, state) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+            let (mut wallets, mut tx_log) = (state.wallets, state.tx_log);
+            let resume = state.resume;
+            for (i, t) in rows.iter().enumerate() {
+                if resume.is_some_and(|r| r.covers(0, i as u64 + 1)) {
+                    continue;
+                }
+                let _ = engine::process(&config, t, &mut wallets, &mut tx_log);
+            }
+            assert_eq!(balances(&wallets), balances(&expected.0), "{}", crash_at);
+            assert_eq!(states(&rows, &tx_log), expected_states, "{}", crash_at);
+            assert_eq!(tx_log.len(), expected.1.len());
+            assert_eq!(tx_log.now(), expected.1.now());
+        }
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(snapshot_path(&path));
+    }
+
+    /// Snapshots get further apart as the transaction log grows, also once
+    /// recovered
+    #[test]
+    fn snapshot_interval() {
+        use crate::transaction::Type;
+
+        let config = Config::default();
+        let path = std::env::temp_dir().join(format!("pay-engine-interval-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(snapshot_path(&path));
+        let (journal, state) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+        let mut journal = journal.with_snapshot_every(10);
+        let (mut wallets, mut tx_log) = (state.wallets, state.tx_log);
+        let mut snapshots = vec![];
+        for id in 1..=1_000 {
+            let t = Transaction::new(Type::Deposit, 1, id, Amount::from_units(1));
+            engine::process(&config, &t, &mut wallets, &mut tx_log).unwrap();
+            let offset = Offset {
+                input: 0,
+                line: id as u64,
+                byte: 0,
+            };
+            journal.append(&t, offset, tx_log.now()).unwrap();
+            if journal.snapshot_due() {
+                journal.snapshot(&wallets, &tx_log).unwrap();
+                snapshots.push(id);
+            }
+        }
+        assert_eq!(snapshots, vec![10, 20, 40, 80, 160, 320, 640]);
+        drop(journal);
+
+        let (journal, _) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+        let journal = journal.with_snapshot_every(10);
+        assert_eq!(journal.since_snapshot, 360);
+        assert!(!journal.snapshot_due());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(snapshot_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn torn_entry() {
+        use crate::transaction::Type;
+
+        let config = Config::default();
+        let path = std::env::temp_dir().join(format!("pay-engine-torn-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (mut journal, _) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+        let deposit = Transaction::new(Type::Deposit, 1, 1, "1.5".parse().unwrap());
+        let offset = Offset {
+            input: 0,
+            line: 2,
+            byte: 21,
+        };
+        journal.append(&deposit, offset, 1).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+        let complete = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"seq\":2,\"ti")
+            .unwrap();
+
+        let (_, state) = Journal::recover(&path, &config, HashMap::new()).unwrap();
+        assert_eq!(state.resume, Some(offset));
+        assert_eq!(state.tx_log.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,17 @@
+pub mod amount;
+pub mod asset;
 pub mod client;
 pub mod engine;
 pub mod error;
+pub mod fee;
+pub mod journal;
+pub mod output;
+pub mod parallel;
 pub mod parser;
+pub mod report;
+pub mod risk;
+pub mod snapshot;
+pub mod storage;
 pub mod transaction;
 
 pub use error::Result;
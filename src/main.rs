@@ -1,68 +1,547 @@
-use parser::Parser;
+use clap::Parser as CliParser;
+use client::ClientWallets;
+use engine::{Engine, LockPolicy};
+use fee::{Fee, Fees};
+use journal::{Journal, Offset};
+use output::Format;
+use parallel::{ParallelEngine, Rejected};
+use parser::{Mode, ParseIssue, Parser, Position};
 use pay_engine::*;
+use report::RejectReport;
+use risk::RiskRules;
+use snapshot::Snapshot;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 use std::time::Instant;
-use transaction::{utils::RandomTransactions, Transaction};
+use transaction::{utils::RandomTransactions, DisputeWindow, Transaction, TransactionLog};
 
 use simple_logger::SimpleLogger;
+use storage::{DenseTxStore, DiskTxStore, TxStore};
+
+/// A toy payment engine.
+///
+/// Applies the transactions of the input CSV files to the clients' wallets,
+/// and prints the final balances of the clients.
+#[derive(CliParser, Debug)]
+#[command(name = "pay-engine")]
+struct Cli {
+    /// CSV files of transactions, processed in order. `-` reads from stdin.
+    /// Without any input, random transactions are generated instead.
+    inputs: Vec<String>,
+
+    /// Write the balances to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Format of the balances: csv, jsonl or json
+    #[arg(short, long, default_value = "csv")]
+    format: Format,
+
+    /// Write every rejected row to this CSV file (`-` for stderr)
+    #[arg(long)]
+    rejects: Option<String>,
+
+    /// Stop at the first row that cannot be parsed, and exit with an error
+    #[arg(long)]
+    strict: bool,
+
+    /// Number of random transactions to generate when no input is given
+    #[arg(long, default_value_t = 1_000_000)]
+    count: usize,
+
+    /// Seed of the random transactions, to replay a given run
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Only allow disputes on the N most recent deposits and withdrawals
+    #[arg(long, value_name = "N", conflicts_with = "window_ticks")]
+    window_transactions: Option<usize>,
+
+    /// Only allow disputes on deposits and withdrawals submitted less than N
+    /// rows ago
+    #[arg(long, value_name = "N")]
+    window_ticks: Option<u64>,
+
+    /// Fee on deposits: flat (`0.5`), a percentage (`1.5%`), or tiers of
+    /// either by amount (`0:1.0,100:0.5%`)
+    #[arg(long, value_name = "FEE")]
+    deposit_fee: Option<Fee>,
+
+    /// Fee on withdrawals, on top of the amount withdrawn, see --deposit-fee
+    #[arg(long, value_name = "FEE")]
+    withdrawal_fee: Option<Fee>,
+
+    /// Fee on chargebacks, out of what the client has left, see --deposit-fee
+    #[arg(long, value_name = "FEE")]
+    chargeback_fee: Option<Fee>,
+
+    /// What a locked account may still do: reject-all, settle-disputes
+    /// (resolve and chargeback), allow-deposits or allow-disputes
+    #[arg(long, value_name = "POLICY", default_value = "settle-disputes")]
+    lock_policy: LockPolicy,
+
+    /// Allow a transaction to be disputed again once its dispute was
+    /// resolved. Without it, a transaction can only be disputed once.
+    #[arg(long)]
+    allow_redispute: bool,
+
+    /// Client whose wallet collects the fees
+    #[arg(long, value_name = "ID", default_value_t = u16::MAX)]
+    house: u16,
+
+    /// Limits on deposits and withdrawals, global and per client, in a JSON
+    /// file (see the README)
+    #[arg(long, value_name = "PATH")]
+    limits: Option<PathBuf>,
+
+    /// Keep the transaction log in this scratch file instead of in memory.
+    /// Slower, but bounded by the disk rather than by memory.
+    #[arg(long, value_name = "PATH")]
+    tx_store: Option<PathBuf>,
+
+    /// Journal the processed rows to this file, to recover from a crash. A
+    /// run given an existing journal picks up where the previous one stopped.
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+
+    /// Snapshot the state of the engine every N journaled rows at least, and
+    /// no more often than the transaction log grows
+    #[arg(long, value_name = "N", default_value_t = 100_000)]
+    snapshot_every: u64,
+
+    /// Start from the state saved in this snapshot, instead of from scratch
+    #[arg(long, value_name = "PATH", conflicts_with = "journal")]
+    load_state: Option<PathBuf>,
+
+    /// Save the state of the engine to this snapshot once done
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<PathBuf>,
+
+    /// Spread the clients over N threads
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        conflicts_with_all = ["journal", "load_state", "save_state", "tx_store"]
+    )]
+    threads: usize,
+
+    /// Parse the inputs on N threads, ahead of the engine. 0 parses them on
+    /// the engine's thread.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    parse_threads: usize,
+
+    /// Print a summary of what the engine did on stderr
+    #[arg(long)]
+    stats: bool,
+
+    /// Log more (-v warn, -vv info, -vvv debug, -vvvv trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log level (off, error, warn, info, debug, trace). Overrides -v
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+}
+
+impl Cli {
+    fn log_level(&self) -> log::LevelFilter {
+        use log::LevelFilter::*;
+        self.log_level.unwrap_or(match self.verbose {
+            0 => Off,
+            1 => Warn,
+            2 => Info,
+            3 => Debug,
+            _ => Trace,
+        })
+    }
+
+    fn config(&self) -> Result<engine::Config> {
+        let dispute_window = match (self.window_transactions, self.window_ticks) {
+            (Some(n), _) => DisputeWindow::Transactions(n),
+            (None, Some(n)) => DisputeWindow::Ticks(n),
+            (None, None) => DisputeWindow::Unbounded,
+        };
+        let fees = Fees {
+            deposit: self.deposit_fee.clone().unwrap_or_default(),
+            withdrawal: self.withdrawal_fee.clone().unwrap_or_default(),
+            chargeback: self.chargeback_fee.clone().unwrap_or_default(),
+            house: self.house,
+        };
+        let risk = match &self.limits {
+            Some(path) => RiskRules::load(path)?,
+            None => RiskRules::default(),
+        };
+        Ok(engine::Config {
+            lock_policy: self.lock_policy,
+            allow_redispute: self.allow_redispute,
+            dispute_window,
+            fees,
+            risk,
+        })
+    }
+
+    fn mode(&self) -> Mode {
+        if self.strict {
+            Mode::Strict
+        } else {
+            Mode::Lenient
+        }
+    }
+}
+
+/// What happened to the rows of the input
+#[derive(Debug, Default)]
+struct Stats {
+    rows: usize,
+    applied: usize,
+    parse_issues: usize,
+    rejections: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    fn print(&self, runtime: f32) {
+        eprintln!("rows: {}", self.rows);
+        eprintln!("applied: {}", self.applied);
+        eprintln!("parse issues: {}", self.parse_issues);
+        for (reason, count) in &self.rejections {
+            eprintln!("rejected ({}): {}", reason, count);
+        }
+        eprintln!("runtime: {:.04}s", runtime);
+    }
+}
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
     SimpleLogger::new()
-        // change here to enable logs
-        .with_level(log::LevelFilter::Off)
+        .with_level(cli.log_level())
         .init()
         .unwrap();
 
-    // skip program name
-    let mut args = std::env::args();
-    let _prog_name = args.next().expect("USAGE: cargo run");
-
-    run_engine(args.next()).map_err(|e| {
+    run_engine(&cli).map_err(|e| {
         eprintln!("Could not run engine ({})", e);
         error::Error::DeserializeError
     })?;
     Ok(())
 }
 
-/// If a file is given, open it and parse it as CSV.
+/// Run the engine with the transaction log backend the command line asked for
+fn run_engine(cli: &Cli) -> Result<()> {
+    if cli.threads > 1 {
+        return run_parallel(cli);
+    }
+    match &cli.tx_store {
+        Some(path) => run_engine_with(cli, DiskTxStore::create(path)?),
+        None => run_engine_with(cli, DenseTxStore::new()),
+    }
+}
+
+/// If files are given, open them and parse them as CSV.
 /// Otherwise, generate random transactions
-fn run_engine(filepath: Option<String>) -> Result<()> {
-    let gen_random_tx = filepath.is_none();
+fn run_engine_with<S: TxStore>(cli: &Cli, store: S) -> Result<()> {
+    let mut report = open_report(cli)?;
+    let config = cli.config()?;
+    let (mut journal, wallets, tx_log, resume) = match &cli.journal {
+        Some(path) => {
+            let (journal, state) = Journal::recover(path, &config, store)?;
+            let journal = journal.with_snapshot_every(cli.snapshot_every);
+            (Some(journal), state.wallets, state.tx_log, state.resume)
+        }
+        None => {
+            let (wallets, tx_log) = match &cli.load_state {
+                Some(path) => Snapshot::load(path)?.restore(store, config.dispute_window)?,
+                None => (
+                    ClientWallets::new(),
+                    TransactionLog::with_store(store, config.dispute_window),
+                ),
+            };
+            (None, wallets, tx_log, None)
+        }
+    };
+    let mut engine = Engine::from_parts(config, wallets, tx_log);
+    let mut stats = Stats::default();
+    let mut first_issue = None;
 
-    let mut transactions = get_transaction_stream(&filepath)?;
-    let total_transactions = transactions.size_hint().1.unwrap_or(1);
     let before = Instant::now();
-    let (wallets, tx_log) = engine::run(&mut transactions);
+    for (input, row) in get_transaction_stream(cli)? {
+        let line = match &row {
+            Ok((position, _)) => position.line,
+            Err(issue) => issue.position.line,
+        };
+        // processed by a previous run of the journal
+        if resume.is_some_and(|r| r.covers(input, line)) {
+            continue;
+        }
+        stats.rows += 1;
+        let rejected = match row {
+            Ok((position, t)) => {
+                let new_client = engine.client(t.client).is_none();
+                let outcome = engine.submit(&t);
+                // the outcome cannot be trusted if the log lost track of its
+                // transactions
+                engine.tx_log().check_store()?;
+                if let Some(journal) = journal.as_mut() {
+                    if outcome.is_ok() || new_client {
+                        let offset = Offset {
+                            input,
+                            line: position.line,
+                            byte: position.byte,
+                        };
+                        journal.append(&t, offset, engine.tx_log().now())?;
+                    }
+                    if journal.snapshot_due() {
+                        journal.snapshot(engine.wallets(), engine.tx_log())?;
+                    }
+                }
+                match outcome {
+                    Ok(_) => {
+                        stats.applied += 1;
+                        None
+                    }
+                    Err(rejection) => {
+                        *stats.rejections.entry(rejection.to_string()).or_default() += 1;
+                        Some((position.line, t.to_string(), rejection.to_string()))
+                    }
+                }
+            }
+            Err(issue) => {
+                log::warn!("skipping row ({})", issue);
+                stats.parse_issues += 1;
+                let rejected = (
+                    issue.position.line,
+                    issue.record.clone(),
+                    issue.message.clone(),
+                );
+                if cli.strict {
+                    first_issue = Some(issue);
+                }
+                Some(rejected)
+            }
+        };
+        if let (Some(report), Some((line, record, reason))) = (report.as_mut(), rejected) {
+            report.reject(input_name(cli, input), line, &record, &reason)?;
+        }
+        if first_issue.is_some() {
+            break;
+        }
+    }
     let runtime = before.elapsed().as_secs_f32();
+    if let Some(journal) = journal.as_mut() {
+        journal.sync()?;
+    }
+    if let Some(report) = report.as_mut() {
+        report.flush()?;
+    }
+
+    if cli.stats {
+        stats.print(runtime);
+    }
+    if let Some(issue) = first_issue {
+        return Err(error::Error::InvalidRow(issue));
+    }
+    if let Some(path) = &cli.save_state {
+        let snapshot = Snapshot::take(engine.wallets(), engine.tx_log());
+        engine.tx_log().check_store()?;
+        snapshot.save(path)?;
+    }
+    conclude(cli, engine.wallets(), &stats, runtime)
+}
+
+/// Same as `run_engine_with`, but spreads the clients over several threads
+fn run_parallel(cli: &Cli) -> Result<()> {
+    let mut report = open_report(cli)?;
+    let mut engine = ParallelEngine::new(cli.config()?, cli.threads)?;
+    let mut stats = Stats::default();
+    let mut first_issue = None;
+    // rows that could not be parsed wait here for the rejections of the rows
+    // before them, so that the report follows the order of the input
+    let mut issues = VecDeque::new();
 
-    if !gen_random_tx {
-        wallets.print_balances().map_err(|e| {
+    let before = Instant::now();
+    for (input, row) in get_transaction_stream(cli)? {
+        stats.rows += 1;
+        let row_number = stats.rows;
+        match row {
+            Ok((position, t)) => engine.submit(t, (row_number, input, position.line))?,
+            Err(issue) => {
+                log::warn!("skipping row ({})", issue);
+                stats.parse_issues += 1;
+                if cli.strict {
+                    first_issue = Some(issue.clone());
+                }
+                issues.push_back((row_number, input, issue));
+            }
+        }
+        if row_number % 1024 == 0 || first_issue.is_some() {
+            let rejected = engine.rejections();
+            report_parallel(cli, &mut report, &mut stats, &mut issues, rejected)?;
+        }
+        if first_issue.is_some() {
+            break;
+        }
+    }
+    let (wallets, rejected) = engine.finish()?;
+    report_parallel(cli, &mut report, &mut stats, &mut issues, rejected)?;
+    for (_, input, issue) in issues {
+        if let Some(report) = report.as_mut() {
+            report.reject(
+                input_name(cli, input),
+                issue.position.line,
+                &issue.record,
+                &issue.message,
+            )?;
+        }
+    }
+    let runtime = before.elapsed().as_secs_f32();
+    if let Some(report) = report.as_mut() {
+        report.flush()?;
+    }
+    let rejected: usize = stats.rejections.values().sum();
+    stats.applied = stats.rows - stats.parse_issues - rejected;
+
+    if cli.stats {
+        stats.print(runtime);
+    }
+    if let Some(issue) = first_issue {
+        return Err(error::Error::InvalidRow(issue));
+    }
+    conclude(cli, &wallets, &stats, runtime)
+}
+
+/// Count and report the rows the parallel engine rejected, along with the rows
+/// that could not be parsed before them
+fn report_parallel(
+    cli: &Cli,
+    report: &mut Option<RejectReport<Box<dyn Write>>>,
+    stats: &mut Stats,
+    issues: &mut VecDeque<(usize, usize, ParseIssue)>,
+    rejected: Vec<Rejected<(usize, usize, u64)>>,
+) -> Result<()> {
+    for rejected in rejected {
+        let (row_number, input, line) = rejected.tag;
+        let reason = rejected.rejection.to_string();
+        *stats.rejections.entry(reason.clone()).or_default() += 1;
+        let report = match report.as_mut() {
+            Some(report) => report,
+            None => continue,
+        };
+        while issues.front().is_some_and(|(row, _, _)| *row < row_number) {
+            let (_, input, issue) = issues.pop_front().unwrap();
+            report.reject(
+                input_name(cli, input),
+                issue.position.line,
+                &issue.record,
+                &issue.message,
+            )?;
+        }
+        report.reject(
+            input_name(cli, input),
+            line,
+            &rejected.transaction.to_string(),
+            &reason,
+        )?;
+    }
+    Ok(())
+}
+
+/// Open the report of rejected rows, if the command line asked for one
+fn open_report(cli: &Cli) -> Result<Option<RejectReport<Box<dyn Write>>>> {
+    Ok(match cli.rejects.as_deref() {
+        None => None,
+        Some("-") => Some(RejectReport::new(
+            Box::new(std::io::stderr()) as Box<dyn Write>
+        )),
+        Some(path) => Some(RejectReport::new(
+            Box::new(File::create(path)?) as Box<dyn Write>
+        )),
+    })
+}
+
+/// Name of input `input` in the report of rejected rows
+fn input_name(cli: &Cli, input: usize) -> &str {
+    cli.inputs.get(input).map_or("random", String::as_str)
+}
+
+/// Write the balances, and the summary of a run on random transactions
+fn conclude(cli: &Cli, wallets: &ClientWallets, stats: &Stats, runtime: f32) -> Result<()> {
+    let gen_random_tx = cli.inputs.is_empty();
+    if !gen_random_tx || cli.output.is_some() {
+        write_balances(cli, wallets).map_err(|e| {
             eprintln!("Could not serialize wallet balances ({})", e);
             error::Error::SerializeError
         })?;
-    } else {
+    }
+    if gen_random_tx {
         println!(
             "Executed {} successfull transactions ({}%) out of {} in {:.04}s",
-            tx_log.len(),
-            ((100 * tx_log.len()) / total_transactions),
-            total_transactions,
+            stats.applied,
+            ((100 * stats.applied) / stats.rows.max(1)),
+            stats.rows,
             runtime
         );
     }
     Ok(())
 }
 
+/// Write the balances where the command line asked for
+fn write_balances(cli: &Cli, wallets: &ClientWallets) -> Result<()> {
+    match &cli.output {
+        Some(path) => {
+            output::write_balances(wallets, cli.format, BufWriter::new(File::create(path)?))
+        }
+        None => output::write_balances(wallets, cli.format, std::io::stdout().lock()),
+    }
+}
+
+/// A row of the input: the index of the input it comes from, and the
+/// transaction it holds along with its position.
+type Row = (
+    usize,
+    std::result::Result<(Position, Transaction), ParseIssue>,
+);
+
 /// get a trait object for our transactions
-fn get_transaction_stream(
-    filepath: &Option<String>,
-) -> Result<Box<dyn Iterator<Item = Transaction>>> {
-    if let Some(file) = filepath {
-        match Parser::new(&file) {
-            Ok(p) => return Ok(Box::new(p)),
+fn get_transaction_stream(cli: &Cli) -> Result<Box<dyn Iterator<Item = Row>>> {
+    if cli.inputs.is_empty() {
+        let generator = match cli.seed {
+            Some(seed) => RandomTransactions::with_seed(seed),
+            None => RandomTransactions::new(),
+        };
+        return Ok(Box::new(generator.take(cli.count).enumerate().map(
+            |(i, t)| {
+                let position = Position {
+                    line: i as u64 + 1,
+                    byte: 0,
+                };
+                (0, Ok((position, t)))
+            },
+        )));
+    }
+
+    let mut parsers = Vec::with_capacity(cli.inputs.len());
+    for file in &cli.inputs {
+        match Parser::new(file) {
+            Ok(p) => parsers.push(p.with_mode(cli.mode())),
             Err(err) => {
                 eprintln!("{}", err);
                 return Err(err);
             }
         }
     }
-    return Ok(Box::new(RandomTransactions::new().take(1_000_000)));
+    if cli.parse_threads > 0 {
+        let workers = cli.parse_threads;
+        return Ok(Box::new(parsers.into_iter().enumerate().flat_map(
+            move |(input, parser)| parser.pipelined(workers).map(move |row| (input, row)),
+        )));
+    }
+    Ok(Box::new(parsers.into_iter().enumerate().flat_map(
+        |(input, mut parser)| {
+            std::iter::from_fn(move || {
+                let row = parser.next()?;
+                Some((input, row.map(|t| (parser.last_position(), t))))
+            })
+        },
+    )))
 }
@@ -0,0 +1,185 @@
+//! Writes the final balances of the clients
+use crate::amount::Amount;
+use crate::asset::Asset;
+use crate::client::{Balance, Client, ClientWallets};
+use crate::error::{Error, Result};
+use crate::storage::WalletStore;
+
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The formats balances can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// One CSV row per balance, with a header
+    #[default]
+    Csv,
+    /// One JSON object per line, per balance
+    JsonLines,
+    /// A single, indented, JSON array of balances
+    Json,
+}
+
+/// The balance of a client in a single asset, as written out
+#[derive(Serialize)]
+struct Row {
+    client: u16,
+    /// only written when some client holds funds in an asset other than the
+    /// default one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<Asset>,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// One row per asset the client holds funds in, or a single row of empty
+/// balances
+fn rows(client: &Client, with_asset: bool) -> Vec<Row> {
+    let empty = [Balance::default()];
+    let balances = match client.balances() {
+        [] => &empty[..],
+        balances => balances,
+    };
+    balances
+        .iter()
+        .map(|b| Row {
+            client: client.id(),
+            asset: if with_asset { Some(b.asset()) } else { None },
+            available: b.available(),
+            held: b.held(),
+            total: b.total(),
+            locked: client.is_locked(),
+        })
+        .collect()
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            "json" => Ok(Format::Json),
+            other => Err(Error::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+/// Write the balances of every client in `wallets` to `w`, sorted by client id.
+///
+/// Clients get one row per asset, sorted by asset. Balances only get an
+/// `asset` column if some of them are not in the default asset, so that
+/// single-asset inputs get the same output as before assets existed.
+pub fn write_balances<S: WalletStore, W: Write>(
+    wallets: &ClientWallets<S>,
+    format: Format,
+    mut w: W,
+) -> Result<()> {
+    let clients = wallets.clients();
+    let with_asset = clients
+        .iter()
+        .flat_map(|c| c.balances())
+        .any(|b| !b.asset().is_default());
+    let rows: Vec<Row> = clients
+        .into_iter()
+        .flat_map(|c| rows(c, with_asset))
+        .collect();
+    match format {
+        Format::Csv => {
+            let mut wtr = csv::Writer::from_writer(w);
+            for row in &rows {
+                wtr.serialize(row)?;
+            }
+            wtr.flush()?;
+        }
+        Format::JsonLines => {
+            for row in &rows {
+                serde_json::to_writer(&mut w, row)?;
+                w.write_all(b"\n")?;
+            }
+            w.flush()?;
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut w, &rows)?;
+            w.write_all(b"\n")?;
+            w.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wallets() -> ClientWallets {
+        let mut wallets = ClientWallets::new();
+        wallets
+            .get_or_create_mut(2)
+            .credit("2.0".parse().unwrap())
+            .unwrap();
+        let client = wallets.get_or_create_mut(1);
+        client.credit("1.5".parse().unwrap()).unwrap();
+        client.hold("0.25".parse().unwrap()).unwrap();
+        wallets
+    }
+
+    fn render(format: Format) -> String {
+        let mut buf = Vec::new();
+        write_balances(&wallets(), format, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            render(Format::Csv),
+            "client,available,held,total,locked\n\
+             1,1.25,0.25,1.5,false\n\
+             2,2.0,0.0,2.0,false\n"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(
+            render(Format::JsonLines),
+            "{\"client\":1,\"available\":\"1.25\",\"held\":\"0.25\",\"total\":\"1.5\",\"locked\":false}\n\
+             {\"client\":2,\"available\":\"2.0\",\"held\":\"0.0\",\"total\":\"2.0\",\"locked\":false}\n"
+        );
+    }
+
+    #[test]
+    fn assets() {
+        let mut wallets = wallets();
+        let usd = "USD".parse().unwrap();
+        let client = wallets.get_or_create_mut(2);
+        client
+            .balance_mut(usd)
+            .credit("3.0".parse().unwrap())
+            .unwrap();
+        client.lock();
+        wallets.get_or_create_mut(3);
+        let mut buf = Vec::new();
+        write_balances(&wallets, Format::Csv, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,asset,available,held,total,locked\n\
+             1,,1.25,0.25,1.5,false\n\
+             2,,2.0,0.0,2.0,true\n\
+             2,USD,3.0,0.0,3.0,true\n\
+             3,,0.0,0.0,0.0,false\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let parsed: serde_json::Value = serde_json::from_str(&render(Format::Json)).unwrap();
+        assert_eq!(parsed[1]["client"], 2);
+        assert_eq!(parsed[0]["held"], "0.25");
+    }
+}
@@ -0,0 +1,597 @@
+//! Multi-threaded engine, sharding the clients across worker threads
+//!
+//! Every client belongs to one shard, and every shard has its own `Engine`,
+//! run by its own thread. The transactions of a client are executed in the
+//! order they were submitted, by the shard the client belongs to.
+//!
+//! Transaction ids are shared by all clients though: a deposit reusing the id
+//! of another client's deposit is a duplicate, and a dispute can reference
+//! the transaction of another client. To get the same outcome as a single
+//! engine, we remember which shard first used every id, in a set of ids per
+//! shard, as compact as the one the transaction log keeps of the ids that aged
+//! out. When a transaction refers to an id first used on another shard, every
+//! shard finishes what it was given, and we look at the shard owning the id to
+//! know how the transaction would have ended up.
+//! This is rare in practice, as clients do not reuse each other's ids.
+//!
+//! Transfers change the wallets of two clients. When they live on different
+//! shards, the transfer, and later its dispute events, are executed by the
+//! dispatcher itself, once every shard finished what it was given.
+use crate::client::{Client, ClientWallets};
+use crate::engine::{Config, Engine, Outcome, Rejection};
+use crate::error::{Error, Result};
+use crate::transaction::{DisputeWindow, IdSet, Transaction, Type};
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Number of transactions handed to a shard at once
+const BATCH: usize = 1024;
+/// Number of batches a shard can be behind before the dispatcher waits for it
+const QUEUE: usize = 8;
+
+/// A transaction the engine refused
+#[derive(Debug)]
+pub struct Rejected<T> {
+    /// What the transaction was submitted with
+    pub tag: T,
+    pub transaction: Transaction,
+    pub rejection: Rejection,
+}
+
+/// A transaction handed to a shard
+struct Job<T> {
+    /// Number of transactions submitted before this one, plus one
+    seq: u64,
+    tag: T,
+    transaction: Transaction,
+    /// Rejection found by looking at another shard
+    foreign: Option<Rejection>,
+}
+
+enum Message<T> {
+    Batch(Vec<Job<T>>),
+    /// Acknowledge once every previous batch was executed
+    Barrier(SyncSender<()>),
+}
+
+/// What a shard did with a batch
+struct Report<T> {
+    shard: usize,
+    /// seq of the last transaction of the batch
+    processed: u64,
+    applied: usize,
+    rejected: Vec<(u64, Rejected<T>)>,
+}
+
+struct Shard<T> {
+    engine: Arc<Mutex<Engine>>,
+    sender: SyncSender<Message<T>>,
+    worker: JoinHandle<()>,
+    /// transactions not sent to the worker yet
+    batch: Vec<Job<T>>,
+    /// seq of the last transaction given to the shard
+    assigned: u64,
+    /// seq of the last transaction the shard executed
+    processed: u64,
+}
+
+/// An engine running on several threads.
+///
+/// Transactions are submitted with a tag, given back along with the
+/// transaction if it gets rejected. Rejections come back in the order the
+/// transactions were submitted.
+///
+/// Dispute windows counted in transactions are not supported, as they depend
+/// on the order of transactions across every client. Nor are fees, as every
+/// transaction paying one would change the wallet of the house account.
+pub struct ParallelEngine<T: Send + 'static = ()> {
+    shards: Vec<Shard<T>>,
+    /// ids first used by the clients of each shard, see `owner()`
+    used: Vec<IdSet>,
+    /// ids of the transfers between clients of different shards
+    transfers: IdSet,
+    /// seq of the last submitted transaction
+    seq: u64,
+    reports: Receiver<Report<T>>,
+    /// rejections received from the shards, by seq, not given back yet
+    rejected: BTreeMap<u64, Rejected<T>>,
+    applied: usize,
+}
+
+impl<T: Send + 'static> ParallelEngine<T> {
+    /// Start an engine with `threads` shards
+    pub fn new(config: Config, threads: usize) -> Result<Self> {
+        if let DisputeWindow::Transactions(_) = config.dispute_window {
+            return Err(Error::Unsupported(
+                "dispute windows counted in transactions",
+            ));
+        }
+        if !config.fees.is_free() {
+            return Err(Error::Unsupported("fees"));
+        }
+        let (report_sender, reports) = channel();
+        let shards: Vec<_> = (0..threads.max(1))
+            .map(|id| {
+                let engine = Arc::new(Mutex::new(Engine::with_config(config.clone())));
+                let (sender, receiver) = sync_channel(QUEUE);
+                let worker = {
+                    let engine = Arc::clone(&engine);
+                    let reports = report_sender.clone();
+                    thread::spawn(move || work(id, &engine, receiver, reports))
+                };
+                Shard {
+                    engine,
+                    sender,
+                    worker,
+                    batch: Vec::with_capacity(BATCH),
+                    assigned: 0,
+                    processed: 0,
+                }
+            })
+            .collect();
+        Ok(ParallelEngine {
+            used: vec![IdSet::default(); shards.len()],
+            shards,
+            transfers: IdSet::default(),
+            seq: 0,
+            reports,
+            rejected: BTreeMap::new(),
+            applied: 0,
+        })
+    }
+
+    /// Submit a transaction. It is executed later on, by the shard of its
+    /// client.
+    pub fn submit(&mut self, t: Transaction, tag: T) -> Result<()> {
+        self.seq += 1;
+        let shard = self.shard_of(t.client);
+        let foreign = self.foreign(&t, shard)?;
+        if foreign.is_none() && self.crosses_shards(&t, shard) {
+            return self.execute_here(t, tag, shard);
+        }
+        let job = Job {
+            seq: self.seq,
+            tag,
+            transaction: t,
+            foreign,
+        };
+        let target = &mut self.shards[shard];
+        target.batch.push(job);
+        target.assigned = self.seq;
+        if target.batch.len() >= BATCH {
+            send_batch(target)?;
+        }
+        self.collect();
+        Ok(())
+    }
+
+    /// Number of transactions the shards applied so far
+    pub fn applied(&self) -> usize {
+        self.applied
+    }
+
+    /// The rejections of every transaction that was executed, and that no
+    /// transaction submitted before is still waiting for
+    pub fn rejections(&mut self) -> Vec<Rejected<T>> {
+        self.collect();
+        let pending = self
+            .shards
+            .iter()
+            .filter(|s| s.processed < s.assigned)
+            .map(|s| s.processed + 1)
+            .min()
+            .unwrap_or(u64::MAX);
+        let later = self.rejected.split_off(&pending);
+        std::mem::replace(&mut self.rejected, later)
+            .into_values()
+            .collect()
+    }
+
+    /// Wait for every submitted transaction to be executed, stop the threads,
+    /// and get back the wallets of every client, along with the last
+    /// rejections
+    pub fn finish(mut self) -> Result<(ClientWallets, Vec<Rejected<T>>)> {
+        for shard in &mut self.shards {
+            send_batch(shard)?;
+        }
+        let mut wallets = ClientWallets::new();
+        for shard in self.shards {
+            drop(shard.sender);
+            shard.worker.join().map_err(|_| Error::WorkerDied)?;
+            let engine = Arc::try_unwrap(shard.engine)
+                .map_err(|_| Error::WorkerDied)?
+                .into_inner()
+                .map_err(|_| Error::WorkerDied)?;
+            for client in engine.balances() {
+                *wallets.get_or_create_mut(client.id()) = client.clone();
+            }
+        }
+        for report in self.reports.try_iter() {
+            self.applied += report.applied;
+            self.rejected.extend(report.rejected);
+        }
+        Ok((wallets, self.rejected.into_values().collect()))
+    }
+
+    /// Check if `t`, about to be executed on `shard`, may change the wallet
+    /// of a client of another shard
+    fn crosses_shards(&mut self, t: &Transaction, shard: usize) -> bool {
+        match (&t.r#type, t.to) {
+            (Type::Transfer, Some(to)) if self.shard_of(to) != shard => {
+                self.transfers.insert(t.id);
+                true
+            }
+            (Type::Dispute | Type::Resolve | Type::Chargeback, _) => self.transfers.contains(t.id),
+            _ => false,
+        }
+    }
+
+    /// Execute `t` right away, once every shard executed what it was given,
+    /// with the wallets of the client of `shard` and of its counterparty
+    fn execute_here(&mut self, t: Transaction, tag: T, shard: usize) -> Result<()> {
+        self.barrier()?;
+        let seq = self.seq;
+        let outcome = {
+            let mut engine = self.shards[shard]
+                .engine
+                .lock()
+                .map_err(|_| Error::WorkerDied)?;
+            // the counterparty is looked up at the time the transaction runs
+            engine.advance_to(seq - 1);
+            match engine.counterparty(&t) {
+                Some(to) if self.shard_of(to) != shard => {
+                    let mut other = self.shards[self.shard_of(to)]
+                        .engine
+                        .lock()
+                        .map_err(|_| Error::WorkerDied)?;
+                    let mut wallet = other
+                        .client(to)
+                        .cloned()
+                        .unwrap_or_else(|| Client::with_id(to));
+                    let outcome = engine.submit_with_at(seq, &t, &mut wallet);
+                    if outcome.is_ok() {
+                        other.set_client(wallet);
+                    }
+                    outcome
+                }
+                _ => engine.submit_at(seq, &t, None),
+            }
+        };
+        match outcome {
+            Ok(_) => self.applied += 1,
+            Err(rejection) => {
+                let rejected = Rejected {
+                    tag,
+                    transaction: t,
+                    rejection,
+                };
+                self.rejected.insert(seq, rejected);
+            }
+        }
+        Ok(())
+    }
+
+    fn shard_of(&self, client: u16) -> usize {
+        client as usize % self.shards.len()
+    }
+
+    /// Shard whose clients first used id `tx_id`, looking at `shard` first,
+    /// where most ids are used again
+    fn owner(&self, tx_id: u32, shard: usize) -> Option<usize> {
+        if self.used[shard].contains(tx_id) {
+            return Some(shard);
+        }
+        (0..self.used.len()).find(|s| self.used[*s].contains(tx_id))
+    }
+
+    /// Find out if `t`, about to be executed on `shard`, is rejected because
+    /// of a transaction that lives on another shard
+    fn foreign(&mut self, t: &Transaction, shard: usize) -> Result<Option<Rejection>> {
+        let owner = match self.owner(t.id, shard) {
+            Some(owner) if owner != shard => owner,
+            Some(_) => return Ok(None),
+            None => {
+                if let Type::Deposit | Type::Withdrawal | Type::Transfer = t.r#type {
+                    self.used[shard].insert(t.id);
+                }
+                return Ok(None);
+            }
+        };
+        self.barrier()?;
+        let mut engine = self.shards[owner]
+            .engine
+            .lock()
+            .map_err(|_| Error::WorkerDied)?;
+        // the shard only knows about the time of its own transactions. `t`
+        // looks at the log once its own tick passed, like on a single engine.
+        engine.advance_to(self.seq);
+        Ok(match t.r#type {
+            Type::Deposit | Type::Withdrawal | Type::Transfer => {
+                if engine.tx_log().contains(t.id) {
+                    Some(Rejection::DuplicateTxId)
+                } else {
+                    // the first use of the id was rejected, it is free
+                    drop(engine);
+                    self.used[owner].remove(t.id);
+                    self.used[shard].insert(t.id);
+                    None
+                }
+            }
+            _ => {
+                if engine.transaction(t.id).is_some() {
+                    Some(Rejection::ClientMismatch)
+                } else if engine.tx_log().is_expired(t.id) {
+                    Some(Rejection::DisputeWindowExpired)
+                } else {
+                    None
+                }
+            }
+        })
+    }
+
+    /// Wait for every shard to execute everything it was given
+    fn barrier(&mut self) -> Result<()> {
+        let mut acks = Vec::with_capacity(self.shards.len());
+        for shard in &mut self.shards {
+            send_batch(shard)?;
+            let (ack, done) = sync_channel(1);
+            shard
+                .sender
+                .send(Message::Barrier(ack))
+                .map_err(|_| Error::WorkerDied)?;
+            acks.push(done);
+        }
+        for done in acks {
+            done.recv().map_err(|_| Error::WorkerDied)?;
+        }
+        self.collect();
+        Ok(())
+    }
+
+    /// Go through the reports the shards sent so far
+    fn collect(&mut self) {
+        while let Ok(report) = self.reports.try_recv() {
+            let shard = &mut self.shards[report.shard];
+            shard.processed = shard.processed.max(report.processed);
+            self.applied += report.applied;
+            self.rejected.extend(report.rejected);
+        }
+    }
+}
+
+fn send_batch<T>(shard: &mut Shard<T>) -> Result<()> {
+    if shard.batch.is_empty() {
+        return Ok(());
+    }
+    let batch = std::mem::replace(&mut shard.batch, Vec::with_capacity(BATCH));
+    shard
+        .sender
+        .send(Message::Batch(batch))
+        .map_err(|_| Error::WorkerDied)
+}
+
+/// Execute the batches of shard `id`, until the dispatcher hangs up
+fn work<T>(
+    id: usize,
+    engine: &Mutex<Engine>,
+    receiver: Receiver<Message<T>>,
+    reports: Sender<Report<T>>,
+) {
+    for message in receiver {
+        let batch = match message {
+            Message::Batch(batch) => batch,
+            Message::Barrier(ack) => {
+                let _ = ack.send(());
+                continue;
+            }
+        };
+        let mut engine = engine
+            .lock()
+            .expect("the dispatcher never panics holding a shard");
+        let mut report = Report {
+            shard: id,
+            processed: 0,
+            applied: 0,
+            rejected: Vec::new(),
+        };
+        for job in batch {
+            let outcome: Outcome = engine.submit_at(job.seq, &job.transaction, job.foreign);
+            match outcome {
+                Ok(_) => report.applied += 1,
+                Err(rejection) => report.rejected.push((
+                    job.seq,
+                    Rejected {
+                        tag: job.tag,
+                        transaction: job.transaction,
+                        rejection,
+                    },
+                )),
+            }
+            report.processed = job.seq;
+        }
+        drop(engine);
+        if reports.send(report).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::risk::{Deposits, Limits, RiskRules, Withdrawn};
+    use crate::transaction::utils::{GeneratorConfig, RandomTransactions};
+
+    /// Run `rows` on a single engine, and on a parallel one, and make sure
+    /// they agree on everything
+    fn compare(config: Config, rows: &[Transaction], threads: usize) {
+        let mut single = Engine::with_config(config.clone());
+        let expected: Vec<_> = rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, t)| single.submit(t).err().map(|r| (i, r)))
+            .collect();
+
+        let mut parallel = ParallelEngine::new(config, threads).unwrap();
+        let mut rejected = Vec::new();
+        for (i, t) in rows.iter().enumerate() {
+            parallel.submit(t.clone(), i).unwrap();
+            rejected.extend(parallel.rejections());
+        }
+        let (wallets, last) = parallel.finish().unwrap();
+        rejected.extend(last);
+        let rejected: Vec<_> = rejected.iter().map(|r| (r.tag, r.rejection)).collect();
+
+        assert_eq!(rejected, expected);
+        let json = |clients: Vec<&crate::client::Client>| serde_json::to_string(&clients).unwrap();
+        assert_eq!(json(wallets.clients()), json(single.balances()));
+    }
+
+    #[test]
+    fn same_as_single_engine() {
+        let rows: Vec<_> = RandomTransactions::with_seed(11).take(50_000).collect();
+        compare(Config::default(), &rows, 4);
+    }
+
+    /// Few ids shared by a lot of clients, so that most transactions refer to
+    /// another shard
+    #[test]
+    fn shared_ids() {
+        let generator = GeneratorConfig {
+            clients: 1..=40,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            new_id_probability: 0.05,
+            id_spread: 64,
+            ..GeneratorConfig::default()
+        };
+        let rows: Vec<_> = RandomTransactions::with_config(generator, 5)
+            .unwrap()
+            .take(20_000)
+            .collect();
+        let config = Config {
+            dispute_window: DisputeWindow::Ticks(500),
+            ..Config::default()
+        };
+        compare(config, &rows, 3);
+    }
+
+    /// Half the withdrawals turned into transfers, mostly between clients of
+    /// different shards
+    #[test]
+    fn transfers() {
+        let generator = GeneratorConfig {
+            type_weights: [30, 30, 20, 10, 1],
+            clients: 1..=8,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            new_id_probability: 0.5,
+            id_spread: 64,
+            ..GeneratorConfig::default()
+        };
+        let rows: Vec<_> = RandomTransactions::with_config(generator, 3)
+            .unwrap()
+            .take(20_000)
+            .map(|mut t| {
+                if let (Type::Withdrawal, 0) = (&t.r#type, t.id % 2) {
+                    t.r#type = Type::Transfer;
+                    t.to = Some(t.client % 8 + 1);
+                }
+                t
+            })
+            .collect();
+        compare(Config::default(), &rows, 3);
+    }
+
+    /// Transfers between shards, and ids reused once they aged out of the
+    /// dispute window
+    #[test]
+    fn window_transfers() {
+        let generator = GeneratorConfig {
+            type_weights: [30, 30, 20, 10, 1],
+            clients: 1..=12,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            new_id_probability: 0.3,
+            id_spread: 256,
+            ..GeneratorConfig::default()
+        };
+        let rows: Vec<_> = RandomTransactions::with_config(generator, 9)
+            .unwrap()
+            .take(20_000)
+            .map(|mut t| {
+                if let (Type::Withdrawal, 0) = (&t.r#type, t.id % 2) {
+                    t.r#type = Type::Transfer;
+                    t.to = Some(t.client % 12 + 1);
+                }
+                t
+            })
+            .collect();
+        let config = Config {
+            dispute_window: DisputeWindow::Ticks(100),
+            allow_redispute: true,
+            ..Config::default()
+        };
+        compare(config, &rows, 3);
+    }
+
+    /// The windows of the limits follow the ticks of the whole stream, not
+    /// those of a shard, transfers between shards included
+    #[test]
+    fn limits() {
+        let generator = GeneratorConfig {
+            clients: 1..=20,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            ..GeneratorConfig::default()
+        };
+        let rows: Vec<_> = RandomTransactions::with_config(generator, 7)
+            .unwrap()
+            .take(20_000)
+            .map(|mut t| {
+                if let (Type::Withdrawal, 0) = (&t.r#type, t.id % 2) {
+                    t.r#type = Type::Transfer;
+                    t.to = Some(t.client % 20 + 1);
+                }
+                t
+            })
+            .collect();
+        let amt = |s: &str| -> Amount { s.parse().unwrap() };
+        let config = Config {
+            risk: RiskRules {
+                global: Limits {
+                    max_withdrawal: Some(amt("8.0")),
+                    withdrawn: Some(Withdrawn {
+                        total: amt("20.0"),
+                        ticks: 200,
+                    }),
+                    deposits: Some(Deposits {
+                        count: 3,
+                        ticks: 100,
+                    }),
+                    max_balance: Some(amt("40.0")),
+                },
+                clients: vec![(
+                    3,
+                    Limits {
+                        max_balance: Some(amt("10.0")),
+                        ..Limits::default()
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+            ..Config::default()
+        };
+        compare(config, &rows, 4);
+    }
+
+    #[test]
+    fn unsupported_window() {
+        let config = Config {
+            dispute_window: DisputeWindow::Transactions(10),
+            ..Config::default()
+        };
+        assert!(ParallelEngine::<()>::new(config, 2).is_err());
+    }
+}
@@ -1,33 +1,666 @@
-use crate::transaction::Transaction;
+use crate::amount::Amount;
+use crate::asset::Asset;
+use crate::transaction::{Transaction, Type};
 use crate::Result;
-use csv::{Reader, ReaderBuilder};
+use csv::{ByteRecord, Reader, ReaderBuilder, StringRecord};
+use flate2::read::MultiGzDecoder;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use thiserror::Error;
 
-pub struct Parser {
-    reader: Reader<std::fs::File>,
-    record: csv::StringRecord,
+/// How the parser reacts to rows it cannot read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Stop at the first bad row
+    Strict,
+    /// Report bad rows, and keep going
+    #[default]
+    Lenient,
+}
+
+/// Where a row starts in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: u64,
+    pub byte: u64,
+}
+
+impl From<&csv::Position> for Position {
+    fn from(p: &csv::Position) -> Self {
+        Position {
+            line: p.line(),
+            byte: p.byte(),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, byte {}", self.line, self.byte)
+    }
+}
+
+/// The different kinds of problems the parser can run into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The input could not be read. Nothing can be parsed past this point.
+    Io,
+    /// The row does not describe a valid transaction
+    Malformed,
+}
+
+/// A row of the input that could not be turned into a transaction
+#[derive(Debug, Clone, Error)]
+#[error("{position}: {message}")]
+pub struct ParseIssue {
+    pub kind: IssueKind,
+    pub position: Position,
+    /// The fields of the row, as found in the input
+    pub record: String,
+    pub message: String,
+}
+
+/// A source of input the parser can read from
+pub type Input = Box<dyn Read + Send>;
+
+pub struct Parser<R: Read = Input> {
+    reader: Reader<R>,
+    record: ByteRecord,
+    mode: Mode,
+    issues: Vec<ParseIssue>,
+    done: bool,
 }
 
 impl Parser {
-    /// Create a new parser that reads from the given file
+    /// Create a new parser that reads from the given file.
+    ///
+    /// `-` reads from stdin, and files ending in `.gz` are decompressed on
+    /// the fly.
     pub fn new(file_path: &str) -> Result<Parser> {
+        let input: Input = if file_path == "-" {
+            Box::new(std::io::stdin())
+        } else if file_path.ends_with(".gz") {
+            Box::new(MultiGzDecoder::new(File::open(file_path)?))
+        } else {
+            Box::new(File::open(file_path)?)
+        };
+        Ok(Parser::from_reader(input))
+    }
+}
+
+impl<R: Read> Parser<R> {
+    /// Create a new parser that reads CSV from any source
+    pub fn from_reader(input: R) -> Self {
         let rdr = ReaderBuilder::new()
             .flexible(true)
             .trim(csv::Trim::All)
-            .from_path(file_path)?;
-        Ok(Parser {
+            .from_reader(input);
+        Parser {
             reader: rdr,
-            record: csv::StringRecord::new(),
-        })
+            record: ByteRecord::new(),
+            mode: Mode::default(),
+            issues: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Change how the parser reacts to bad rows
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Every issue encountered so far
+    pub fn issues(&self) -> &[ParseIssue] {
+        &self.issues
+    }
+
+    /// Position of the last row that was read
+    pub fn last_position(&self) -> Position {
+        self.record
+            .position()
+            .map(Position::from)
+            .unwrap_or_default()
+    }
+
+    /// Fields of the last row that was read, joined by commas
+    pub fn last_record(&self) -> String {
+        join(&self.record)
+    }
+
+    fn issue(&mut self, kind: IssueKind, position: Position, message: String) -> ParseIssue {
+        let issue = ParseIssue {
+            kind,
+            position,
+            record: self.last_record(),
+            message,
+        };
+        self.track(issue)
+    }
+
+    /// Keep track of an issue, and stop if it is fatal
+    fn track(&mut self, issue: ParseIssue) -> ParseIssue {
+        let kind = issue.kind;
+        if kind == IssueKind::Io || self.mode == Mode::Strict {
+            self.done = true;
+        }
+        self.issues.push(issue.clone());
+        issue
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = std::result::Result<Transaction, ParseIssue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                let position = e.position().map(Position::from).unwrap_or_default();
+                let kind = match e.kind() {
+                    csv::ErrorKind::Io(_) => IssueKind::Io,
+                    _ => IssueKind::Malformed,
+                };
+                self.record.clear();
+                return Some(Err(self.issue(kind, position, e.to_string())));
+            }
+        }
+        match deserialize(&self.record) {
+            Ok(t) => Some(Ok(t)),
+            Err(issue) => Some(Err(self.track(issue))),
+        }
+    }
+}
+
+/// Fields of a row, joined by commas
+fn join(record: &ByteRecord) -> String {
+    record
+        .iter()
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Turn a CSV row into a transaction.
+///
+/// Well-formed rows are parsed straight from their bytes. Anything else goes
+/// through serde, which knows how to explain what is wrong with the row, and
+/// is tolerant of a few more things (hexadecimal ids, unicode whitespace...).
+fn deserialize(record: &ByteRecord) -> std::result::Result<Transaction, ParseIssue> {
+    if let Some(t) = parse_fast(record) {
+        return Ok(t);
+    }
+    let position = record.position().map(Position::from).unwrap_or_default();
+    let malformed = |record, message| ParseIssue {
+        kind: IssueKind::Malformed,
+        position,
+        record,
+        message,
+    };
+    let mut record = match StringRecord::from_byte_record(record.clone()) {
+        Ok(record) => record,
+        // same as reading a `StringRecord` in the first place
+        Err(e) => {
+            let message = match record.position() {
+                Some(pos) => format!(
+                    "CSV parse error: record {} (line {}, field: {}, byte: {}): {}",
+                    pos.record(),
+                    pos.line(),
+                    e.utf8_error().field(),
+                    pos.byte(),
+                    e.utf8_error()
+                ),
+                None => format!(
+                    "CSV parse error: field {}: {}",
+                    e.utf8_error().field(),
+                    e.utf8_error()
+                ),
+            };
+            return Err(malformed(String::new(), message));
+        }
+    };
+    // the reader only trimmed ASCII whitespace
+    record.trim();
+    record.deserialize(None).map_err(|e| {
+        let message = match e.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => e.to_string(),
+        };
+        malformed(record.iter().collect::<Vec<_>>().join(","), message)
+    })
+}
+
+/// Parse a row made of a type, a client, a transaction id, and an optional
+/// amount, asset and recipient, all plain ASCII. Returns `None` for any other
+/// row.
+fn parse_fast(record: &ByteRecord) -> Option<Transaction> {
+    if !(3..=6).contains(&record.len()) {
+        return None;
+    }
+    let r#type = match &record[0] {
+        b"deposit" => Type::Deposit,
+        b"withdrawal" => Type::Withdrawal,
+        b"dispute" => Type::Dispute,
+        b"resolve" => Type::Resolve,
+        b"chargeback" => Type::Chargeback,
+        b"transfer" => Type::Transfer,
+        _ => return None,
+    };
+    let client = parse_u32(&record[1]).and_then(|c| u16::try_from(c).ok())?;
+    let id = parse_u32(&record[2])?;
+    let amount = match record.get(3) {
+        Some(amount) if !amount.is_empty() => Amount::from_ascii(amount)?,
+        _ => Amount::default(),
+    };
+    let asset = match record.get(4) {
+        Some(asset) => Asset::from_ascii(asset)?,
+        None => Asset::default(),
+    };
+    let t = Transaction::new(r#type, client, id, amount).with_asset(asset);
+    match record.get(5) {
+        Some(to) if !to.is_empty() => {
+            Some(t.with_recipient(parse_u32(to).and_then(|to| u16::try_from(to).ok())?))
+        }
+        _ => Some(t),
+    }
+}
+
+/// Parse a non-empty string of ASCII digits
+fn parse_u32(s: &[u8]) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut n: u32 = 0;
+    for b in s {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((b - b'0') as u32)?;
     }
+    Some(n)
 }
 
-impl Iterator for Parser {
-    type Item = Transaction;
+/// Number of rows read before they are handed to the deserialize threads
+const PIPELINE_BATCH: usize = 4096;
+/// Number of batches waiting for a thread of the pipeline, per thread
+const PIPELINE_QUEUE: usize = 4;
+
+/// A row of the input, and where it was read
+type PipelineItem = std::result::Result<(Position, Transaction), ParseIssue>;
+
+/// A batch of rows read from the input, numbered in reading order
+type RawBatch = (u64, Vec<std::result::Result<ByteRecord, ParseIssue>>);
+
+impl<R: Read + Send + 'static> Parser<R> {
+    /// Parse the input on other threads, ahead of whoever consumes the
+    /// transactions.
+    ///
+    /// One thread splits the input in rows, and `workers` threads turn them
+    /// into transactions. Rows come out in input order, along with their
+    /// position, see `Pipeline`.
+    pub fn pipelined(self, workers: usize) -> Pipeline {
+        let workers = workers.max(1);
+        let mode = self.mode;
+        let (raw_sender, raw) = sync_channel::<RawBatch>(PIPELINE_QUEUE * workers);
+        let (parsed_sender, parsed) = sync_channel(PIPELINE_QUEUE * workers);
+        let raw = Arc::new(Mutex::new(raw));
+
+        let mut threads = vec![thread::spawn(move || read(self, raw_sender))];
+        for _ in 0..workers {
+            let raw = Arc::clone(&raw);
+            let parsed = parsed_sender.clone();
+            threads.push(thread::spawn(move || loop {
+                // hold the lock only while waiting for a batch
+                let next = raw.lock().map(|raw| raw.recv());
+                let (seq, batch) = match next {
+                    Ok(Ok(batch)) => batch,
+                    _ => return,
+                };
+                let batch: Vec<PipelineItem> = batch
+                    .into_iter()
+                    .map(|row| {
+                        let record = row?;
+                        let position = record.position().map(Position::from).unwrap_or_default();
+                        deserialize(&record).map(|t| (position, t))
+                    })
+                    .collect();
+                if parsed.send((seq, batch)).is_err() {
+                    return;
+                }
+            }));
+        }
+        Pipeline {
+            parsed,
+            pending: BTreeMap::new(),
+            next: 0,
+            current: Vec::new().into_iter(),
+            mode,
+            done: false,
+            threads,
+        }
+    }
+}
+
+/// Split the input in rows, and hand them over in batches
+fn read<R: Read>(mut parser: Parser<R>, raw: SyncSender<RawBatch>) {
+    let mut seq = 0;
+    let mut batch = Vec::with_capacity(PIPELINE_BATCH);
+    loop {
+        let mut record = ByteRecord::new();
+        let end = match parser.reader.read_byte_record(&mut record) {
+            Ok(true) => {
+                batch.push(Ok(record));
+                false
+            }
+            Ok(false) => true,
+            Err(e) => {
+                let position = e.position().map(Position::from).unwrap_or_default();
+                let kind = match e.kind() {
+                    csv::ErrorKind::Io(_) => IssueKind::Io,
+                    _ => IssueKind::Malformed,
+                };
+                batch.push(Err(parser.issue(kind, position, e.to_string())));
+                parser.done
+            }
+        };
+        if end || batch.len() >= PIPELINE_BATCH {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(PIPELINE_BATCH));
+            // the consumer hung up, no need to read any further
+            if raw.send((seq, full)).is_err() {
+                return;
+            }
+            seq += 1;
+        }
+        if end {
+            return;
+        }
+    }
+}
+
+/// Transactions parsed ahead by other threads, see `Parser::pipelined()`.
+///
+/// Reading and parsing wait whenever the consumer is too far behind. Problems
+/// come out in input order, like with `Parser`: in `Strict` mode, nothing comes
+/// out after the first one.
+pub struct Pipeline {
+    parsed: Receiver<(u64, Vec<PipelineItem>)>,
+    /// batches that came in before the ones preceding them
+    pending: BTreeMap<u64, Vec<PipelineItem>>,
+    /// number of the next batch to go through
+    next: u64,
+    current: std::vec::IntoIter<PipelineItem>,
+    mode: Mode,
+    done: bool,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Iterator for Pipeline {
+    type Item = PipelineItem;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.reader.read_record(&mut self.record).unwrap_or(false) {
-            true => self.record.deserialize(None).ok(),
-            false => None,
+        if self.done {
+            return None;
+        }
+        loop {
+            if let Some(item) = self.current.next() {
+                if let Err(issue) = &item {
+                    if issue.kind == IssueKind::Io || self.mode == Mode::Strict {
+                        self.done = true;
+                    }
+                }
+                return Some(item);
+            }
+            while !self.pending.contains_key(&self.next) {
+                match self.parsed.recv() {
+                    Ok((seq, batch)) => {
+                        self.pending.insert(seq, batch);
+                    }
+                    // every thread is done
+                    Err(_) => {
+                        self.done = true;
+                        return None;
+                    }
+                }
+            }
+            let batch = self.pending.remove(&self.next).unwrap_or_default();
+            self.current = batch.into_iter();
+            self.next += 1;
         }
     }
 }
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // hang up, so that the threads stop at their next batch
+        let (_, parsed) = sync_channel(0);
+        drop(std::mem::replace(&mut self.parsed, parsed));
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parser(name: &str, mode: Mode) -> Parser {
+        Parser::new(&format!("inputs/{}", name))
+            .expect("fixture exists")
+            .with_mode(mode)
+    }
+
+    #[test]
+    fn lenient() {
+        let mut p = parser("invalid_field_value.csv", Mode::Lenient);
+        let rows: Vec<_> = p.by_ref().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].is_ok());
+        let issue = rows[1].as_ref().unwrap_err();
+        assert_eq!(issue.kind, IssueKind::Malformed);
+        assert_eq!(issue.position, Position { line: 3, byte: 62 });
+        assert_eq!(issue.record, "deposit,1,h,10.1");
+        assert_eq!(p.issues().len(), 1);
+    }
+
+    #[test]
+    fn strict() {
+        let mut p = parser("sample4.csv", Mode::Strict);
+        let issue = p.next().unwrap().unwrap_err();
+        assert_eq!(issue.position.line, 2);
+        assert!(p.next().is_none());
+    }
+
+    #[test]
+    fn from_memory() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.5\ndispute, 1, 1\n";
+        let txs: Vec<_> = Parser::from_reader(input.as_bytes())
+            .collect::<std::result::Result<_, _>>()
+            .expect("valid input");
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].amount, "1.5".parse().unwrap());
+    }
+
+    #[test]
+    fn gzip() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("pay-engine-{}.csv.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder
+            .write_all(&std::fs::read("inputs/sample1.csv").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let parsed = Parser::new(path.to_str().unwrap()).unwrap().count();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(parsed, 5);
+    }
+
+    #[test]
+    fn position_of_valid_rows() {
+        let mut p = parser("sample1.csv", Mode::Strict);
+        p.next().unwrap().unwrap();
+        p.next().unwrap().unwrap();
+        assert_eq!(p.last_position().line, 3);
+        assert_eq!(p.last_record(), "deposit,2,2,2.0");
+    }
+
+    /// The pipeline gives the same rows and issues as the parser, in the same
+    /// order
+    #[test]
+    fn pipelined() {
+        for entry in std::fs::read_dir("inputs").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if !name.ends_with(".csv") {
+                continue;
+            }
+            for mode in &[Mode::Lenient, Mode::Strict] {
+                let mut serial = parser(name, *mode);
+                let mut expected = Vec::new();
+                while let Some(row) = serial.next() {
+                    expected.push(row.map(|t| (serial.last_position(), t)));
+                }
+                let rows: Vec<_> = parser(name, *mode).pipelined(3).collect();
+                assert_eq!(format!("{:?}", rows), format!("{:?}", expected), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn pipelined_batches() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 0..3 * PIPELINE_BATCH {
+            input.push_str(&format!("deposit,1,{},1.0\n", id));
+        }
+        input.push_str("deposit,1,x,1.0\n");
+        let rows: Vec<_> = Parser::from_reader(std::io::Cursor::new(input))
+            .pipelined(2)
+            .collect();
+        assert_eq!(rows.len(), 3 * PIPELINE_BATCH + 1);
+        for (i, row) in rows.iter().take(3 * PIPELINE_BATCH).enumerate() {
+            let (position, t) = row.as_ref().unwrap();
+            assert_eq!((position.line, t.id), (i as u64 + 2, i as u32));
+        }
+        assert!(rows.last().unwrap().is_err());
+
+        // hanging up early stops the threads
+        let input = "type,client,tx,amount\n".to_string() + &"deposit,1,1,1.0\n".repeat(100_000);
+        let mut pipeline = Parser::from_reader(std::io::Cursor::new(input)).pipelined(2);
+        assert!(pipeline.next().unwrap().is_ok());
+        drop(pipeline);
+    }
+
+    /// What `deserialize()` gives for every row of `input`, next to what serde
+    /// alone gives
+    fn differential(input: &[u8]) {
+        let builder = || {
+            let mut builder = ReaderBuilder::new();
+            builder.flexible(true).trim(csv::Trim::All);
+            builder
+        };
+        let mut strings = builder().from_reader(input);
+        let mut bytes = builder().from_reader(input);
+        let mut string = StringRecord::new();
+        let mut byte = ByteRecord::new();
+        while bytes.read_byte_record(&mut byte).unwrap() {
+            let expected = match strings.read_record(&mut string) {
+                Ok(_) => string
+                    .deserialize::<Transaction>(None)
+                    .map_err(|e| match e.kind() {
+                        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                        _ => e.to_string(),
+                    }),
+                Err(e) => Err(e.to_string()),
+            };
+            let got = deserialize(&byte).map_err(|issue| issue.message);
+            assert_eq!(
+                format!("{:?}", got),
+                format!("{:?}", expected),
+                "{}",
+                join(&byte)
+            );
+        }
+    }
+
+    #[test]
+    fn fast_path_over_inputs() {
+        for entry in std::fs::read_dir("inputs").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "csv") {
+                differential(&std::fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn fast_path_edge_cases() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.5\n\
+            \tdeposit ,  2 , 3 ,  4.0001  \n\
+            dispute,1,1\n\
+            dispute,1,1,\n\
+            resolve,1,1,,\n\
+            resolve,1,1,,USD\n\
+            deposit,1,5,2.5,BTC\n\
+            transfer,1,6,1.5,,2\n\
+            transfer,1,6,1.5,BTC,2\n\
+            deposit,1,5,2.5,\n\
+            transfer,1,6,1.5,,\n\
+            transfer,1,6,1.5,,65536\n\
+            transfer,1,6,1.5,,two\n\
+            deposit,1,5,2.5,usd-x\n\
+            deposit,1,5,2.5,ABCDEFGHI\n\
+            deposit,1,5,2.5,USD,extra\n\
+            withdrawal,1,2,1,extra\n\
+            deposit,0x10,0x20,1\n\
+            deposit,+1,2,1\n\
+            deposit,65535,4294967295,-0.5\n\
+            deposit,65536,1,1\n\
+            deposit,1,4294967296,1\n\
+            deposit,1,1,1.00001\n\
+            deposit,1,1,922337203685477.5808\n\
+            deposit,1,1,.5\n\
+            deposit,1,1,5.\n\
+            Deposit,1,1,1\n\
+            deposit,,1,1\n\
+            deposit,1\n\
+            deposit,\u{a0}1\u{a0},2,3\n\
+            deposit,1,2,\u{e9}\n";
+        differential(input.as_bytes());
+
+        // well-formed rows never go through serde
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        let fast: Vec<_> = reader
+            .byte_records()
+            .map(|r| parse_fast(&r.unwrap()).is_some())
+            .take(9)
+            .collect();
+        assert_eq!(fast, vec![true; 9]);
+
+        // csv gets the position of such rows wrong, only the row is checked
+        let mut invalid_utf8 = ByteRecord::from(vec![&b"deposit"[..], b"1", b"2", b"3\xff"]);
+        invalid_utf8.set_position(Some(csv::Position::new()));
+        let issue = deserialize(&invalid_utf8).unwrap_err();
+        assert!(issue.message.contains("invalid UTF-8 in field 3"));
+        assert_eq!(issue.record, "");
+    }
+}
@@ -0,0 +1,84 @@
+//! Report of the input rows that did not make it into the balances
+use crate::Result;
+
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::Write;
+
+/// A row of the rejection report
+#[derive(Serialize)]
+struct Rejected<'a> {
+    input: &'a str,
+    line: u64,
+    record: &'a str,
+    reason: String,
+}
+
+/// Writes one CSV row per rejected input row, giving the input it comes from,
+/// its line number in that input, its raw content and why it was rejected.
+pub struct RejectReport<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> RejectReport<W> {
+    /// Create a report that writes to `w`
+    pub fn new(w: W) -> Self {
+        RejectReport {
+            writer: csv::Writer::from_writer(w),
+        }
+    }
+
+    /// Record that the row found on `line` of `input` was rejected
+    pub fn reject(
+        &mut self,
+        input: &str,
+        line: u64,
+        record: &str,
+        reason: &dyn Display,
+    ) -> Result<()> {
+        self.writer.serialize(Rejected {
+            input,
+            line,
+            record,
+            reason: reason.to_string(),
+        })?;
+        Ok(())
+    }
+
+    /// Make sure every rejection reached the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut buf = Vec::new();
+        {
+            let mut report = RejectReport::new(&mut buf);
+            report
+                .reject("in.csv", 3, "withdrawal,1,2,3.0", &"insufficient funds")
+                .unwrap();
+            report
+                .reject(
+                    "in.csv",
+                    4,
+                    "deposit,1,h,1.0",
+                    &"invalid digit, found in field",
+                )
+                .unwrap();
+            report.flush().unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "input,line,record,reason\n\
+             in.csv,3,\"withdrawal,1,2,3.0\",insufficient funds\n\
+             in.csv,4,\"deposit,1,h,1.0\",\"invalid digit, found in field\"\n"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Limits on what clients may deposit and withdraw, checked before their
+//! balances change
+//!
+//! Inputs carry no timestamps, so windows are counted in ticks: the number of
+//! transactions submitted to the engine, like `DisputeWindow::Ticks`.
+use crate::amount::Amount;
+use crate::asset::Asset;
+use crate::client::Client;
+use crate::error::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// The limit a transaction would break
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    MaxWithdrawal,
+    Withdrawn,
+    Deposits,
+    MaxBalance,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Limit::MaxWithdrawal => "single withdrawal",
+            Limit::Withdrawn => "amount withdrawn",
+            Limit::Deposits => "number of deposits",
+            Limit::MaxBalance => "balance",
+        })
+    }
+}
+
+/// At most `total` withdrawn in an asset within `ticks` ticks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Withdrawn {
+    pub total: Amount,
+    pub ticks: u64,
+}
+
+/// At most `count` deposits within `ticks` ticks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Deposits {
+    pub count: usize,
+    pub ticks: u64,
+}
+
+/// Limits on the transactions of a client. Amounts apply to each asset
+/// separately.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest amount a single withdrawal may take
+    pub max_withdrawal: Option<Amount>,
+    /// Largest amount withdrawn over a rolling window
+    pub withdrawn: Option<Withdrawn>,
+    /// Most deposits over a rolling window
+    pub deposits: Option<Deposits>,
+    /// Largest total balance a deposit may lead to
+    pub max_balance: Option<Amount>,
+}
+
+/// The limits of every client: the global ones, and those of some clients,
+/// which take precedence over the global ones they set
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskRules {
+    pub global: Limits,
+    pub clients: HashMap<u16, Limits>,
+}
+
+impl RiskRules {
+    /// Read the rules from a JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        serde_json::from_reader(BufReader::new(File::open(path)?)).map_err(Error::InvalidLimits)
+    }
+
+    /// Checks if no client has any limit
+    pub fn is_empty(&self) -> bool {
+        self.global == Limits::default() && self.clients.values().all(|l| *l == Limits::default())
+    }
+
+    /// The limit of client `client` picked by `f`
+    fn get<T>(&self, client: u16, f: impl Fn(&Limits) -> Option<T>) -> Option<T> {
+        self.clients
+            .get(&client)
+            .and_then(&f)
+            .or_else(|| f(&self.global))
+    }
+
+    /// Make sure `client` may withdraw `amount` of `asset` at tick `now`
+    pub fn check_withdrawal(
+        &self,
+        client: &Client,
+        asset: Asset,
+        amount: Amount,
+        now: u64,
+    ) -> std::result::Result<(), Limit> {
+        if let Some(max) = self.get(client.id(), |l| l.max_withdrawal) {
+            if amount > max {
+                return Err(Limit::MaxWithdrawal);
+            }
+        }
+        if let Some(window) = self.get(client.id(), |l| l.withdrawn) {
+            let withdrawn = client.activity().map_or(Some(amount), |a| {
+                a.withdrawals(now, window.ticks)
+                    .filter(|(a, _)| *a == asset)
+                    .try_fold(amount, |total, (_, amount)| total.checked_add(amount))
+            });
+            // a total out of range is above any limit
+            if withdrawn.is_none_or(|withdrawn| withdrawn > window.total) {
+                return Err(Limit::Withdrawn);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure `client` may deposit `amount` of `asset` at tick `now`.
+    /// `amount` is what the deposit credits, once fees are paid.
+    pub fn check_deposit(
+        &self,
+        client: &Client,
+        asset: Asset,
+        amount: Amount,
+        now: u64,
+    ) -> std::result::Result<(), Limit> {
+        if let Some(window) = self.get(client.id(), |l| l.deposits) {
+            let deposits = client
+                .activity()
+                .map_or(0, |a| a.deposits(now, window.ticks).count());
+            if deposits >= window.count {
+                return Err(Limit::Deposits);
+            }
+        }
+        if let Some(max) = self.get(client.id(), |l| l.max_balance) {
+            let total = client.balance(asset).total().checked_add(amount);
+            if total.is_none_or(|total| total > max) {
+                return Err(Limit::MaxBalance);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember that `client` withdrew `amount` of `asset` at tick `now`, if
+    /// a window needs it
+    pub(crate) fn record_withdrawal(
+        &self,
+        client: &mut Client,
+        asset: Asset,
+        amount: Amount,
+        now: u64,
+    ) {
+        if let Some(window) = self.get(client.id(), |l| l.withdrawn) {
+            let activity = client.activity_mut();
+            activity.withdrawals.push_back((now, asset, amount));
+            expire(&mut activity.withdrawals, now, window.ticks, |w| w.0);
+        }
+    }
+
+    /// Remember that `client` deposited at tick `now`, if a window needs it
+    pub(crate) fn record_deposit(&self, client: &mut Client, now: u64) {
+        if let Some(window) = self.get(client.id(), |l| l.deposits) {
+            let activity = client.activity_mut();
+            activity.deposits.push_back(now);
+            expire(&mut activity.deposits, now, window.ticks, |d| *d);
+        }
+    }
+}
+
+/// The recent deposits and withdrawals of a client, oldest first, as far back
+/// as their limits look
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Activity {
+    /// tick, asset and amount of each withdrawal
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    withdrawals: VecDeque<(u64, Asset, Amount)>,
+    /// tick of each deposit
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    deposits: VecDeque<u64>,
+}
+
+/// Checks if tick `tick` is less than `ticks` ticks before `now`
+fn within(tick: u64, now: u64, ticks: u64) -> bool {
+    now - tick < ticks
+}
+
+impl Activity {
+    /// The asset and amount of the withdrawals of the last `ticks` ticks
+    fn withdrawals(&self, now: u64, ticks: u64) -> impl Iterator<Item = (Asset, Amount)> + '_ {
+        self.withdrawals
+            .iter()
+            .rev()
+            .take_while(move |(tick, _, _)| within(*tick, now, ticks))
+            .map(|(_, asset, amount)| (*asset, *amount))
+    }
+
+    /// The ticks of the deposits of the last `ticks` ticks
+    fn deposits(&self, now: u64, ticks: u64) -> impl Iterator<Item = u64> + '_ {
+        self.deposits
+            .iter()
+            .rev()
+            .copied()
+            .take_while(move |tick| within(*tick, now, ticks))
+    }
+}
+
+/// Forget the entries of `queue`, oldest first, that are `ticks` ticks or more
+/// before `now`
+fn expire<T>(queue: &mut VecDeque<T>, now: u64, ticks: u64, tick: impl Fn(&T) -> u64) {
+    while queue.front().is_some_and(|e| !within(tick(e), now, ticks)) {
+        queue.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().expect("valid amount")
+    }
+
+    #[test]
+    fn limits() {
+        let rules = RiskRules {
+            global: Limits {
+                max_withdrawal: Some(amt("10")),
+                withdrawn: Some(Withdrawn {
+                    total: amt("15"),
+                    ticks: 3,
+                }),
+                ..Limits::default()
+            },
+            clients: vec![(
+                2,
+                Limits {
+                    max_withdrawal: Some(amt("100")),
+                    deposits: Some(Deposits { count: 1, ticks: 2 }),
+                    max_balance: Some(amt("50")),
+                    ..Limits::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+        assert!(!rules.is_empty());
+        assert!(RiskRules::default().is_empty());
+
+        let asset = Asset::default();
+        let mut client = Client::with_id(1);
+        assert_eq!(
+            rules.check_withdrawal(&client, asset, amt("10.0001"), 1),
+            Err(Limit::MaxWithdrawal)
+        );
+        assert_eq!(rules.check_withdrawal(&client, asset, amt("10"), 1), Ok(()));
+        rules.record_withdrawal(&mut client, asset, amt("10"), 1);
+        assert_eq!(
+            rules.check_withdrawal(&client, asset, amt("5.0001"), 3),
+            Err(Limit::Withdrawn)
+        );
+        // other assets have their own total
+        let other = "BTC".parse().unwrap();
+        assert_eq!(rules.check_withdrawal(&client, other, amt("10"), 3), Ok(()));
+        // the first withdrawal leaves the window
+        assert_eq!(rules.check_withdrawal(&client, asset, amt("10"), 4), Ok(()));
+        rules.record_withdrawal(&mut client, asset, amt("10"), 4);
+        assert_eq!(client.activity().unwrap().withdrawals.len(), 1);
+        // no deposit limit for client 1
+        rules.record_deposit(&mut client, 4);
+        assert!(client.activity().unwrap().deposits.is_empty());
+
+        // client 2 overrides the largest withdrawal, keeps the global window
+        let mut client = Client::with_id(2);
+        client.credit(amt("45")).unwrap();
+        assert_eq!(
+            rules.check_withdrawal(&client, asset, amt("20"), 1),
+            Err(Limit::Withdrawn)
+        );
+        assert_eq!(rules.check_withdrawal(&client, asset, amt("15"), 1), Ok(()));
+        assert_eq!(
+            rules.check_deposit(&client, asset, amt("5.0001"), 1),
+            Err(Limit::MaxBalance)
+        );
+        assert_eq!(rules.check_deposit(&client, asset, amt("5"), 1), Ok(()));
+        rules.record_deposit(&mut client, 1);
+        assert_eq!(
+            rules.check_deposit(&client, asset, amt("1"), 2),
+            Err(Limit::Deposits)
+        );
+        assert_eq!(rules.check_deposit(&client, asset, amt("1"), 3), Ok(()));
+
+        // totals out of range are above any limit
+        let max = Amount::from_units(i64::MAX);
+        assert_eq!(
+            rules.check_deposit(&client, asset, max, 3),
+            Err(Limit::MaxBalance)
+        );
+        let rules = RiskRules {
+            global: Limits {
+                withdrawn: Some(Withdrawn {
+                    total: max,
+                    ticks: 3,
+                }),
+                ..Limits::default()
+            },
+            ..RiskRules::default()
+        };
+        let mut client = Client::with_id(1);
+        rules.record_withdrawal(&mut client, asset, amt("1"), 1);
+        assert_eq!(
+            rules.check_withdrawal(&client, asset, max, 1),
+            Err(Limit::Withdrawn)
+        );
+    }
+
+    #[test]
+    fn load() {
+        let rules: RiskRules = serde_json::from_str(
+            r#"{
+                "global": {"max_withdrawal": "10", "withdrawn": {"total": "15", "ticks": 3}},
+                "clients": {"2": {"deposits": {"count": 1, "ticks": 2}}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(rules.global.max_withdrawal, Some(amt("10")));
+        assert_eq!(
+            rules.clients[&2].deposits,
+            Some(Deposits { count: 1, ticks: 2 })
+        );
+        assert!(serde_json::from_str::<RiskRules>(r#"{"global": {"max": "1"}}"#).is_err());
+    }
+}
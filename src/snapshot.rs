@@ -0,0 +1,245 @@
+//! Saving the whole state of the engine, to load it back later
+//!
+//! A snapshot holds every wallet, and every logged transaction along with
+//! where it stands in the dispute process, so that a run can start from the
+//! closing state of a previous one. Snapshots are written as JSON, tagged
+//! with the version of their format.
+use crate::client::{Client, ClientWallets};
+use crate::error::{Error, Result};
+use crate::storage::TxStore;
+use crate::transaction::{DisputeWindow, LogState, TransactionLog};
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+/// Version of the snapshot format. Bump it whenever what a snapshot holds
+/// changes.
+pub const VERSION: u32 = 5;
+
+/// The state of the engine at some point in time
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    version: u32,
+    /// sorted by client id
+    wallets: Vec<Client>,
+    log: LogState,
+}
+
+/// Just enough of a snapshot to know which format it is in
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the wallets and of the transaction log
+    pub fn take<S: TxStore>(wallets: &ClientWallets, tx_log: &TransactionLog<S>) -> Self {
+        Snapshot {
+            version: VERSION,
+            wallets: wallets.clients().into_iter().cloned().collect(),
+            log: tx_log.save_state(),
+        }
+    }
+
+    /// Rebuild the wallets and the transaction log, keeping the logged
+    /// transactions in `store`.
+    ///
+    /// Transactions that aged out of the saved window are gone, so the
+    /// snapshot can only be restored by an engine configured with the same
+    /// `window`.
+    pub fn restore<S: TxStore>(
+        self,
+        store: S,
+        window: DisputeWindow,
+    ) -> Result<(ClientWallets, TransactionLog<S>)> {
+        if self.log.window() != window {
+            return Err(Error::WindowMismatch {
+                saved: self.log.window(),
+                configured: window,
+            });
+        }
+        let mut wallets = ClientWallets::new();
+        for client in self.wallets {
+            let id = client.id();
+            *wallets.get_or_create_mut(id) = client;
+        }
+        Ok((wallets, TransactionLog::from_state(store, self.log)))
+    }
+
+    /// Make sure we know how to read this snapshot
+    pub fn check_version(&self) -> Result<()> {
+        check_version(self.version)
+    }
+
+    /// Write the snapshot to `w`
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read a snapshot from `r`
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        // check the version first, a snapshot in another format would
+        // otherwise only give a confusing deserialization error
+        let header: Header = serde_json::from_slice(&bytes)?;
+        check_version(header.version)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Save the snapshot to the file at `path`, see `save_json()`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(path, self)
+    }
+
+    /// Load the snapshot saved in the file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        Snapshot::read(File::open(path)?)
+    }
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version != VERSION {
+        return Err(Error::SnapshotVersion(version));
+    }
+    Ok(())
+}
+
+/// Write `value` as JSON to the file at `path`.
+///
+/// The file is written next to `path`, synced, then renamed over it, so that
+/// `path` always holds a complete file, even if we die while writing.
+pub(crate) fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::{self, Applied, Config};
+    use crate::storage::DenseTxStore;
+    use crate::transaction::utils::{GeneratorConfig, RandomTransactions};
+    use crate::transaction::{DisputeState, Transaction, Type};
+
+    #[test]
+    fn save_and_load() {
+        let config = Config::default();
+        // few clients and ids, so that most disputes hit a logged transaction
+        let generator = GeneratorConfig {
+            clients: 1..=20,
+            amounts: Amount::from_units(1)..=Amount::from_units(100_000),
+            new_id_probability: 0.5,
+            id_spread: 64,
+            ..GeneratorConfig::default()
+        };
+        let ten: Amount = "10.0".parse().unwrap();
+        let mut rows: Vec<_> = RandomTransactions::with_config(generator.clone(), 3)
+            .unwrap()
+            .take(2_000)
+            .collect();
+        // a dispute left open by the snapshot, closed once loaded
+        rows.push(Transaction::new(Type::Deposit, 100, 1_000_000, ten));
+        rows.push(Transaction::new(
+            Type::Dispute,
+            100,
+            1_000_000,
+            Amount::ZERO,
+        ));
+        let (mut wallets, mut tx_log) = engine::run_with_config(&config, &mut rows.iter().cloned());
+
+        let mut buf = Vec::new();
+        Snapshot::take(&wallets, &tx_log).write(&mut buf).unwrap();
+        let (mut loaded_wallets, mut loaded_log) = Snapshot::read(buf.as_slice())
+            .unwrap()
+            .restore(DenseTxStore::new(), config.dispute_window)
+            .unwrap();
+
+        assert_eq!(loaded_log.len(), tx_log.len());
+        assert_eq!(loaded_log.now(), tx_log.now());
+        let state = |log: &TransactionLog, id| log.find(id).map(|t| t.state());
+        for t in &rows {
+            assert_eq!(state(&loaded_log, t.id), state(&tx_log, t.id));
+            assert_eq!(loaded_log.history(t.id), tx_log.history(t.id));
+        }
+        // funds held by the disputes of the generated rows, and by the last one
+        let held = |w: &ClientWallets| -> Vec<Amount> {
+            w.clients().iter().map(|c| c.held_balance()).collect()
+        };
+        assert_eq!(held(&loaded_wallets), held(&wallets));
+        assert!(held(&loaded_wallets)[..20]
+            .iter()
+            .any(|h| *h > Amount::ZERO));
+        assert_eq!(loaded_wallets.get(100).unwrap().held_balance(), ten);
+        assert_eq!(state(&loaded_log, 1_000_000), Some(DisputeState::Disputed));
+
+        // both engines keep going the same way
+        let resolve = Transaction::new(Type::Resolve, 100, 1_000_000, Amount::ZERO);
+        assert_eq!(
+            engine::process(&config, &resolve, &mut loaded_wallets, &mut loaded_log),
+            Ok(Applied::Resolved)
+        );
+        assert_eq!(
+            loaded_wallets.get(100).unwrap().held_balance(),
+            Amount::ZERO
+        );
+        engine::process(&config, &resolve, &mut wallets, &mut tx_log).unwrap();
+        for t in RandomTransactions::with_config(generator, 4)
+            .unwrap()
+            .take(2_000)
+        {
+            let expected = engine::process(&config, &t, &mut wallets, &mut tx_log);
+            let outcome = engine::process(&config, &t, &mut loaded_wallets, &mut loaded_log);
+            assert_eq!(outcome, expected);
+        }
+        let json = |w: &ClientWallets| serde_json::to_string(&w.clients()).unwrap();
+        assert_eq!(json(&loaded_wallets), json(&wallets));
+    }
+
+    #[test]
+    fn window_mismatch() {
+        let window = DisputeWindow::Ticks(10);
+        let mut tx_log = TransactionLog::with_window(window);
+        tx_log.push(&Transaction::new(
+            Type::Deposit,
+            1,
+            1,
+            Amount::from_units(1),
+        ));
+        let snapshot = || Snapshot::take(&ClientWallets::new(), &tx_log);
+
+        for configured in &[DisputeWindow::Unbounded, DisputeWindow::Ticks(20)] {
+            match snapshot().restore(DenseTxStore::new(), *configured) {
+                Err(Error::WindowMismatch {
+                    saved,
+                    configured: c,
+                }) => {
+                    assert_eq!(saved, window);
+                    assert_eq!(c, *configured);
+                }
+                otherwise => panic!("{:?}", otherwise.map(|_| ())),
+            }
+        }
+        let (_, loaded) = snapshot().restore(DenseTxStore::new(), window).unwrap();
+        assert_eq!(loaded.len(), 1);
+    }
+
+    #[test]
+    fn unknown_version() {
+        let snapshot = br#"{"version":0,"wallets":[],"log":{}}"#;
+        match Snapshot::read(&snapshot[..]) {
+            Err(Error::SnapshotVersion(0)) => (),
+            otherwise => panic!("{:?}", otherwise),
+        }
+    }
+}
@@ -0,0 +1,933 @@
+//! Backing storage of the transaction log and of the clients' wallets
+//!
+//! `TransactionLog` and `ClientWallets` hold the engine's logic around their
+//! data (dispute window, sorting, ...), and delegate keeping that data to a
+//! store. By default, wallets are kept in a `DenseWallets` and transactions in
+//! a `DenseTxStore`.
+use crate::amount::Amount;
+use crate::asset::Asset;
+use crate::client::Client;
+use crate::error::{Error, Result};
+use crate::transaction::{DisputeState, Transaction, Type};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+#[cfg(not(unix))]
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the transaction log keeps its transactions.
+///
+/// Transactions are handed in and out by value, so a store does not have to
+/// keep them in memory.
+pub trait TxStore {
+    /// Get the transaction with id `tx_id`
+    fn get(&self, tx_id: u32) -> Option<Transaction>;
+    /// Store `t`, replacing any transaction with the same id
+    fn put(&mut self, t: &Transaction);
+    /// Forget about the transaction with id `tx_id`
+    fn remove(&mut self, tx_id: u32);
+    /// Check if the transaction with id `tx_id` is stored
+    fn contains(&self, tx_id: u32) -> bool;
+    /// Number of stored transactions
+    fn len(&self) -> usize;
+    /// Every stored transaction, in no particular order
+    fn transactions(&self) -> Box<dyn Iterator<Item = Transaction> + '_>;
+
+    /// Check if the store holds no transaction
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Report the first error the store ran into since the last check.
+    /// Stores kept in memory never fail.
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl TxStore for HashMap<u32, Transaction> {
+    fn get(&self, tx_id: u32) -> Option<Transaction> {
+        HashMap::get(self, &tx_id).cloned()
+    }
+
+    fn put(&mut self, t: &Transaction) {
+        self.insert(t.id, t.clone());
+    }
+
+    fn remove(&mut self, tx_id: u32) {
+        HashMap::remove(self, &tx_id);
+    }
+
+    fn contains(&self, tx_id: u32) -> bool {
+        self.contains_key(&tx_id)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Transaction> + '_> {
+        Box::new(self.values().cloned())
+    }
+}
+
+/// Where the clients' wallets are kept.
+///
+/// There are at most 65536 clients, so wallets are always small enough to
+/// be handed out by reference.
+pub trait WalletStore {
+    /// Get the wallet of client `client_id`, if it exists
+    fn get(&self, client_id: u16) -> Option<&Client>;
+    /// Get the wallet of client `client_id` to modify it, if it exists
+    fn get_mut(&mut self, client_id: u16) -> Option<&mut Client>;
+    /// Get the wallet of client `client_id`, creating an empty one if needed
+    fn get_or_create_mut(&mut self, client_id: u16) -> &mut Client;
+    /// Every wallet, in no particular order
+    fn wallets(&self) -> Box<dyn Iterator<Item = &Client> + '_>;
+}
+
+impl WalletStore for HashMap<u16, Client> {
+    fn get(&self, client_id: u16) -> Option<&Client> {
+        HashMap::get(self, &client_id)
+    }
+
+    fn get_mut(&mut self, client_id: u16) -> Option<&mut Client> {
+        HashMap::get_mut(self, &client_id)
+    }
+
+    fn get_or_create_mut(&mut self, client_id: u16) -> &mut Client {
+        self.entry(client_id)
+            .or_insert_with(|| Client::with_id(client_id))
+    }
+
+    fn wallets(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+        Box::new(self.values())
+    }
+}
+
+/// Wallets of every possible client, in an array indexed by client id.
+///
+/// The array is only allocated once the first wallet is created, then holds
+/// the 65536 possible wallets, about 2 MiB.
+#[derive(Debug, Default)]
+pub struct DenseWallets {
+    slots: Vec<Option<Client>>,
+}
+
+impl DenseWallets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl WalletStore for DenseWallets {
+    fn get(&self, client_id: u16) -> Option<&Client> {
+        self.slots.get(client_id as usize)?.as_ref()
+    }
+
+    fn get_mut(&mut self, client_id: u16) -> Option<&mut Client> {
+        self.slots.get_mut(client_id as usize)?.as_mut()
+    }
+
+    fn get_or_create_mut(&mut self, client_id: u16) -> &mut Client {
+        if self.slots.is_empty() {
+            self.slots.resize(u16::MAX as usize + 1, None);
+        }
+        self.slots[client_id as usize].get_or_insert_with(|| Client::with_id(client_id))
+    }
+
+    fn wallets(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+        Box::new(self.slots.iter().flatten())
+    }
+}
+
+/// Smallest number of slots of a `DenseTxStore` holding anything
+const MIN_SLOTS: usize = 1024;
+/// Marks a slot of a `DenseTxStore` that never held a transaction
+const EMPTY: u8 = 0;
+/// Marks a slot of a `DenseTxStore` whose transaction was removed
+const REMOVED: u8 = 1;
+/// Asset index of a transaction of a `DenseTxStore` whose asset is set aside
+const OTHER_ASSET: u8 = u8::MAX;
+
+/// A transaction store that only keeps what the log needs of a transaction:
+/// its id, client, amount, asset, type, dispute state, recipient and fee.
+///
+/// Transactions live in an open-addressing hash table, laid out in columns:
+/// 16 bytes per slot, where a `HashMap` of transactions takes 33. Like a
+/// `HashMap`, the table is kept at most 7/8 full. Transfers are expected to be
+/// rare, their recipients are kept on the side. So are fees, which are only
+/// paid when the engine is configured to take them.
+///
+/// The first 255 assets a store meets are kept as an index, past them, the
+/// asset of a transaction is set aside too.
+#[derive(Debug, Default)]
+pub struct DenseTxStore {
+    ids: Vec<u32>,
+    clients: Vec<u16>,
+    amounts: Vec<i64>,
+    /// index of the asset of the transaction in `known_assets`, or
+    /// `OTHER_ASSET`
+    assets: Vec<u8>,
+    /// `EMPTY`, `REMOVED`, or the type and state of the transaction in the
+    /// slot, see `pack()`
+    tags: Vec<u8>,
+    len: usize,
+    removed: usize,
+    /// the first assets transactions were stored in
+    known_assets: Vec<Asset>,
+    /// map of transaction id to its asset, if it is not in `known_assets`
+    other_assets: HashMap<u32, Asset>,
+    /// map of transfer id to its recipient
+    recipients: HashMap<u32, u16>,
+    /// map of transaction id to the fee paid on it, if not zero
+    fees: HashMap<u32, i64>,
+}
+
+impl DenseTxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Slot of the transaction with id `tx_id`, or the slot where it would go
+    /// along with `false` if it is not stored
+    fn slot(&self, tx_id: u32) -> (usize, bool) {
+        let mask = self.tags.len() - 1;
+        // Fibonacci hashing, sequential ids end up spread over the table
+        let mut i = (tx_id.wrapping_mul(0x9E37_79B9) as usize) & mask;
+        let mut free = None;
+        let mut step = 0;
+        loop {
+            match self.tags[i] {
+                EMPTY => return (free.unwrap_or(i), false),
+                REMOVED => {
+                    free.get_or_insert(i);
+                }
+                _ if self.ids[i] == tx_id => return (i, true),
+                _ => (),
+            }
+            // triangular probing, that goes through every slot of a table
+            // whose size is a power of two
+            step += 1;
+            i = (i + step) & mask;
+        }
+    }
+
+    fn find(&self, tx_id: u32) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        match self.slot(tx_id) {
+            (i, true) => Some(i),
+            _ => None,
+        }
+    }
+
+    fn load(&self, i: usize) -> Transaction {
+        let (r#type, state) = unpack(self.tags[i]);
+        let t = Transaction::new(
+            r#type,
+            self.clients[i],
+            self.ids[i],
+            Amount::from_units(self.amounts[i]),
+        )
+        .with_asset(match self.assets[i] {
+            OTHER_ASSET => self.other_assets[&self.ids[i]],
+            index => self.known_assets[index as usize],
+        })
+        .with_state(state);
+        let t = match self.fees.get(&t.id) {
+            Some(fee) => t.with_fee(Amount::from_units(*fee)),
+            None => t,
+        };
+        match self.recipients.get(&t.id) {
+            Some(to) => t.with_recipient(*to),
+            None => t,
+        }
+    }
+
+    /// Index of `asset` in `known_assets`, which it is added to if there is
+    /// room left, or `OTHER_ASSET`
+    fn asset_index(&mut self, asset: Asset) -> u8 {
+        match self.known_assets.iter().position(|a| *a == asset) {
+            Some(index) => index as u8,
+            None if self.known_assets.len() < OTHER_ASSET as usize => {
+                self.known_assets.push(asset);
+                (self.known_assets.len() - 1) as u8
+            }
+            None => OTHER_ASSET,
+        }
+    }
+
+    /// Rebuild the table if it would be more than 7/8 full once `additional`
+    /// more transactions are stored. Removed slots are reclaimed on the way.
+    fn reserve(&mut self, additional: usize) {
+        let full = |len: usize, slots: usize| len * 8 > slots * 7;
+        if !full(self.len + self.removed + additional, self.tags.len()) {
+            return;
+        }
+        let mut size = MIN_SLOTS;
+        // leave some room, not to rebuild the table again right away
+        while full((self.len + additional) * 8 / 7, size) {
+            size *= 2;
+        }
+        let old = std::mem::replace(
+            self,
+            DenseTxStore {
+                ids: vec![0; size],
+                clients: vec![0; size],
+                amounts: vec![0; size],
+                assets: vec![0; size],
+                tags: vec![EMPTY; size],
+                len: 0,
+                removed: 0,
+                known_assets: Vec::new(),
+                other_assets: HashMap::new(),
+                recipients: HashMap::new(),
+                fees: HashMap::new(),
+            },
+        );
+        self.known_assets = old.known_assets.clone();
+        for i in 0..old.tags.len() {
+            if old.tags[i] > REMOVED {
+                self.put(&old.load(i));
+            }
+        }
+    }
+}
+
+impl TxStore for DenseTxStore {
+    fn get(&self, tx_id: u32) -> Option<Transaction> {
+        self.find(tx_id).map(|i| self.load(i))
+    }
+
+    fn put(&mut self, t: &Transaction) {
+        self.reserve(1);
+        let (i, found) = self.slot(t.id);
+        if !found {
+            if self.tags[i] == REMOVED {
+                self.removed -= 1;
+            }
+            self.len += 1;
+        }
+        self.ids[i] = t.id;
+        self.clients[i] = t.client;
+        self.amounts[i] = t.amount.units();
+        self.assets[i] = self.asset_index(t.asset);
+        let other = Some(t.asset).filter(|_| self.assets[i] == OTHER_ASSET);
+        set_aside(&mut self.other_assets, t.id, other);
+        self.tags[i] = pack(&t.r#type, t.state());
+        set_aside(&mut self.recipients, t.id, t.to);
+        let fee = Some(t.fee().units()).filter(|fee| *fee != 0);
+        set_aside(&mut self.fees, t.id, fee);
+    }
+
+    fn remove(&mut self, tx_id: u32) {
+        if let Some(i) = self.find(tx_id) {
+            set_aside(&mut self.recipients, tx_id, None);
+            set_aside(&mut self.fees, tx_id, None);
+            set_aside(&mut self.other_assets, tx_id, None);
+            self.tags[i] = REMOVED;
+            self.len -= 1;
+            self.removed += 1;
+        }
+    }
+
+    fn contains(&self, tx_id: u32) -> bool {
+        self.find(tx_id).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Transaction> + '_> {
+        Box::new(
+            (0..self.tags.len())
+                .filter(move |i| self.tags[*i] > REMOVED)
+                .map(move |i| self.load(i)),
+        )
+    }
+}
+
+/// Keep `value` in `map` as what transaction `tx_id` has, or forget what it
+/// had. Most stores never have anything aside, and skip hashing the id.
+fn set_aside<V>(map: &mut HashMap<u32, V>, tx_id: u32, value: Option<V>) {
+    match value {
+        Some(value) => {
+            map.insert(tx_id, value);
+        }
+        None if !map.is_empty() => {
+            map.remove(&tx_id);
+        }
+        None => (),
+    }
+}
+
+/// Type and state of a transaction in a single byte, never `EMPTY` nor
+/// `REMOVED`
+fn pack(r#type: &Type, state: DisputeState) -> u8 {
+    2 + type_code(r#type) * 4 + state_code(state)
+}
+
+fn unpack(tag: u8) -> (Type, DisputeState) {
+    let tag = tag - 2;
+    (type_of_code(tag / 4), state_of_code(tag % 4))
+}
+
+fn type_code(r#type: &Type) -> u8 {
+    match r#type {
+        Type::Withdrawal => 0,
+        Type::Deposit => 1,
+        Type::Dispute => 2,
+        Type::Resolve => 3,
+        Type::Chargeback => 4,
+        Type::Transfer => 5,
+    }
+}
+
+fn type_of_code(code: u8) -> Type {
+    match code {
+        0 => Type::Withdrawal,
+        1 => Type::Deposit,
+        2 => Type::Dispute,
+        3 => Type::Resolve,
+        4 => Type::Chargeback,
+        _ => Type::Transfer,
+    }
+}
+
+fn state_code(state: DisputeState) -> u8 {
+    match state {
+        DisputeState::Processed => 0,
+        DisputeState::Disputed => 1,
+        DisputeState::Resolved => 2,
+        DisputeState::ChargedBack => 3,
+    }
+}
+
+fn state_of_code(code: u8) -> DisputeState {
+    match code {
+        0 => DisputeState::Processed,
+        1 => DisputeState::Disputed,
+        2 => DisputeState::Resolved,
+        _ => DisputeState::ChargedBack,
+    }
+}
+
+/// Size of a transaction once written to disk
+const RECORD_SIZE: usize = 33;
+/// Smallest number of slots of a `DiskTxStore`
+const MIN_DISK_SLOTS: u64 = 1024;
+/// How many slots are read at once while probing
+const PROBE_BLOCK: u64 = 16;
+/// How many slots are read at once when going through the whole table
+const READ_CHUNK: u64 = 4096;
+
+/// A transaction store backed by a file, so that the transaction log does
+/// not need any memory of its own, however many transactions it holds.
+///
+/// The file is an open-addressing hash table of fixed-size records, like a
+/// `DenseTxStore` but with linear probing, so that a lookup mostly reads a
+/// single block of the file. The table is kept at most half full, and
+/// rebuilt at least twice as large, into a new file next to it, when it gets
+/// there: the file takes 66 to 132 bytes per transaction. It is scratch
+/// space, meant to be thrown away once the engine is done.
+///
+/// Every lookup reads from the file, left to the page cache of the system
+/// to keep fast, and every change writes to it.
+///
+/// I/O errors cannot go through the `TxStore` methods. The store behaves as
+/// if the records it failed to read were missing, and keeps the first error
+/// for `check()` to report, which the engine does after every transaction.
+#[derive(Debug)]
+pub struct DiskTxStore {
+    path: PathBuf,
+    file: File,
+    /// number of slots of the table, a power of two
+    slots: u64,
+    len: usize,
+    removed: usize,
+    /// first slot and records of the block read by the last lookup, which
+    /// the engine mostly looks up again right away to store a transaction
+    block: RefCell<Option<(u64, Vec<u8>)>>,
+    /// first I/O error since the last `check()`
+    error: RefCell<Option<std::io::Error>>,
+}
+
+impl DiskTxStore {
+    /// Create a store backed by the file at `path`, overwriting it
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::with_slots(path.as_ref(), MIN_DISK_SLOTS)?)
+    }
+
+    fn with_slots(path: &Path, slots: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // every slot starts `EMPTY`. Zeros are written out rather than left
+        // as holes, which cost a lot more to fill one record at a time.
+        let zeros = vec![EMPTY; 1 << 20];
+        let mut left = slots * RECORD_SIZE as u64;
+        while left > 0 {
+            let n = left.min(zeros.len() as u64);
+            write_at(
+                &file,
+                &zeros[..n as usize],
+                slots * RECORD_SIZE as u64 - left,
+            )?;
+            left -= n;
+        }
+        Ok(DiskTxStore {
+            path: path.to_path_buf(),
+            file,
+            slots,
+            len: 0,
+            removed: 0,
+            block: RefCell::new(None),
+            error: RefCell::new(None),
+        })
+    }
+
+    /// Keep `e` for `check()`, unless an earlier error is waiting already
+    fn fail(&self, e: std::io::Error) {
+        self.error.borrow_mut().get_or_insert(e);
+    }
+
+    /// Read `count` records, from slot `first` on
+    fn read_slots(&self, first: u64, count: u64) -> std::io::Result<Vec<u8>> {
+        let mut records = vec![EMPTY; (count as usize) * RECORD_SIZE];
+        read_at(&self.file, &mut records, first * RECORD_SIZE as u64)?;
+        Ok(records)
+    }
+
+    /// Read the block of slots probed from slot `first` on, or take it from
+    /// `block` if it was the last one
+    fn read_block(&self, first: u64, count: u64) -> std::io::Result<Vec<u8>> {
+        if let Some((cached, records)) = &*self.block.borrow() {
+            if *cached == first && records.len() == count as usize * RECORD_SIZE {
+                return Ok(records.clone());
+            }
+        }
+        let records = self.read_slots(first, count)?;
+        *self.block.borrow_mut() = Some((first, records.clone()));
+        Ok(records)
+    }
+
+    fn write_slot(&mut self, slot: u64, bytes: &[u8]) -> std::io::Result<()> {
+        // keep the cached block in line with the file, or forget it
+        let cached = self.block.get_mut().take();
+        write_at(&self.file, bytes, slot * RECORD_SIZE as u64)?;
+        if let Some((first, mut records)) = cached {
+            let start = slot.wrapping_sub(first) as usize * RECORD_SIZE;
+            if slot >= first && start < records.len() {
+                records[start..start + bytes.len()].copy_from_slice(bytes);
+                *self.block.get_mut() = Some((first, records));
+            }
+        }
+        Ok(())
+    }
+
+    /// Slot of the transaction with id `tx_id` along with its record, or the
+    /// slot where it would go if it is not stored
+    fn slot(&self, tx_id: u32) -> std::io::Result<(u64, Option<[u8; RECORD_SIZE]>)> {
+        let mask = self.slots - 1;
+        // Fibonacci hashing, on the top bits of the product
+        let bits = self.slots.trailing_zeros();
+        let mut i = (tx_id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - bits);
+        let mut free = None;
+        // the table is never full, there is always an empty slot to stop at
+        loop {
+            let count = PROBE_BLOCK.min(self.slots - i);
+            let records = self.read_block(i, count)?;
+            for (k, bytes) in records.chunks_exact(RECORD_SIZE).enumerate() {
+                let slot = i + k as u64;
+                let mut record = [EMPTY; RECORD_SIZE];
+                record.copy_from_slice(bytes);
+                match record[0] {
+                    EMPTY => return Ok((free.unwrap_or(slot), None)),
+                    REMOVED => {
+                        free.get_or_insert(slot);
+                    }
+                    _ if id_of(&record) == tx_id => return Ok((slot, Some(record))),
+                    _ => (),
+                }
+            }
+            i = (i + count) & mask;
+        }
+    }
+
+    /// The record of transaction `tx_id`, if there is one
+    fn record(&self, tx_id: u32) -> Option<[u8; RECORD_SIZE]> {
+        match self.slot(tx_id) {
+            Ok((_, record)) => record,
+            Err(e) => {
+                self.fail(e);
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, t: &Transaction) -> std::io::Result<()> {
+        let (slot, record) = self.slot(t.id)?;
+        if record.is_none() {
+            self.len += 1;
+        }
+        self.write_slot(slot, &encode(t))
+    }
+
+    /// Rebuild the table if it would be more than half full once `additional`
+    /// more transactions are stored. Removed slots are reclaimed on the way.
+    fn reserve(&mut self, additional: usize) -> std::io::Result<()> {
+        let full = |len: usize, slots: u64| len as u64 * 2 > slots;
+        if !full(self.len + self.removed + additional, self.slots) {
+            return Ok(());
+        }
+        let mut slots = MIN_DISK_SLOTS;
+        // leave some room, not to rebuild the table again right away
+        while full((self.len + additional) * 2, slots) {
+            slots *= 2;
+        }
+        let mut tmp = self.path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let mut table = DiskTxStore::with_slots(Path::new(&tmp), slots)?;
+        for t in self.transactions() {
+            table.insert(&t)?;
+        }
+        if let Some(e) = self.error.borrow_mut().take() {
+            return Err(e);
+        }
+        fs::rename(&tmp, &self.path)?;
+        table.path = self.path.clone();
+        *self = table;
+        Ok(())
+    }
+}
+
+impl TxStore for DiskTxStore {
+    fn get(&self, tx_id: u32) -> Option<Transaction> {
+        self.record(tx_id).map(|record| decode(&record))
+    }
+
+    fn put(&mut self, t: &Transaction) {
+        if let Err(e) = self.reserve(1).and_then(|_| self.insert(t)) {
+            self.fail(e);
+        }
+    }
+
+    fn remove(&mut self, tx_id: u32) {
+        let removed = match self.slot(tx_id) {
+            Ok((slot, Some(_))) => self.write_slot(slot, &[REMOVED]),
+            Ok((_, None)) => return,
+            Err(e) => Err(e),
+        };
+        match removed {
+            Ok(()) => {
+                self.len -= 1;
+                self.removed += 1;
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn contains(&self, tx_id: u32) -> bool {
+        self.record(tx_id).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Transaction> + '_> {
+        let chunks = self.slots.div_ceil(READ_CHUNK);
+        Box::new(
+            (0..chunks)
+                .map(move |chunk| {
+                    let first = chunk * READ_CHUNK;
+                    self.read_slots(first, READ_CHUNK.min(self.slots - first))
+                })
+                .map_while(move |records| match records {
+                    Ok(records) => Some(records),
+                    Err(e) => {
+                        self.fail(e);
+                        None
+                    }
+                })
+                .flat_map(|records| {
+                    records
+                        .chunks_exact(RECORD_SIZE)
+                        .filter(|bytes| bytes[0] > REMOVED)
+                        .map(|bytes| {
+                            let mut record = [EMPTY; RECORD_SIZE];
+                            record.copy_from_slice(bytes);
+                            decode(&record)
+                        })
+                        .collect::<Vec<_>>()
+                }),
+        )
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.error.borrow_mut().take() {
+            Some(e) => Err(Error::TxStore(e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Fill `buf` from `file`, at `offset`
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Write `buf` to `file`, at `offset`
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn write_at(mut file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+fn id_of(record: &[u8; RECORD_SIZE]) -> u32 {
+    let mut id = [0; 4];
+    id.copy_from_slice(&record[1..5]);
+    u32::from_le_bytes(id)
+}
+
+/// Layout: type and state (1 byte, see `pack()`), id (4), client (2),
+/// amount (8), asset (8), recipient of a transfer (2), fee (8), little endian
+fn encode(t: &Transaction) -> [u8; RECORD_SIZE] {
+    let mut record = [EMPTY; RECORD_SIZE];
+    record[0] = pack(&t.r#type, t.state());
+    record[1..5].copy_from_slice(&t.id.to_le_bytes());
+    record[5..7].copy_from_slice(&t.client.to_le_bytes());
+    record[7..15].copy_from_slice(&t.amount.units().to_le_bytes());
+    record[15..23].copy_from_slice(&t.asset.to_bits().to_le_bytes());
+    record[23..25].copy_from_slice(&t.to.unwrap_or_default().to_le_bytes());
+    record[25..33].copy_from_slice(&t.fee().units().to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> Transaction {
+    let (r#type, state) = unpack(record[0]);
+    let mut client = [0; 2];
+    client.copy_from_slice(&record[5..7]);
+    let mut amount = [0; 8];
+    amount.copy_from_slice(&record[7..15]);
+    let mut asset = [0; 8];
+    asset.copy_from_slice(&record[15..23]);
+    let mut to = [0; 2];
+    to.copy_from_slice(&record[23..25]);
+    let mut fee = [0; 8];
+    fee.copy_from_slice(&record[25..33]);
+    let transfer = matches!(r#type, Type::Transfer);
+    let t = Transaction::new(
+        r#type,
+        u16::from_le_bytes(client),
+        id_of(record),
+        Amount::from_units(i64::from_le_bytes(amount)),
+    )
+    .with_asset(Asset::from_bits(u64::from_le_bytes(asset)))
+    .with_fee(Amount::from_units(i64::from_le_bytes(fee)))
+    .with_state(state);
+    match transfer {
+        true => t.with_recipient(u16::from_le_bytes(to)),
+        false => t,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("pay-engine-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn disk_store() {
+        let path = store_path("disk_store");
+        let mut store = DiskTxStore::create(&path).unwrap();
+        let amount = "12.3456".parse().unwrap();
+        // enough records to go through the file, and not only the buffer
+        for id in 0..10_000 {
+            store.put(&Transaction::new(Type::Deposit, 7, id, amount));
+        }
+        let t = Transaction::new(Type::Withdrawal, 3, 1, amount)
+            .with_asset("EUR".parse().unwrap())
+            .with_state(DisputeState::Disputed);
+        store.put(&t);
+        store.put(&Transaction::new(Type::Transfer, 3, 3, amount).with_recipient(9));
+        store.put(&Transaction::new(Type::Deposit, 3, 4, amount).with_fee(amount));
+        store.remove(2);
+
+        assert_eq!(store.len(), 9_999);
+        assert!(!store.contains(2));
+        assert!(store.get(2).is_none());
+        let first = store.get(0).unwrap();
+        assert_eq!((first.id, first.client, first.amount), (0, 7, amount));
+        let updated = store.get(1).unwrap();
+        assert!(matches!(updated.r#type, Type::Withdrawal));
+        assert_eq!(updated.asset.code(), "EUR");
+        assert_eq!(updated.state(), DisputeState::Disputed);
+        assert_eq!(store.get(9_999).unwrap().id, 9_999);
+        assert_eq!(store.get(3).unwrap().to, Some(9));
+        assert_eq!(store.get(4).unwrap().to, None);
+        assert_eq!(store.get(4).unwrap().fee(), amount);
+        assert!(store.get(3).unwrap().fee().is_zero());
+        assert_eq!(store.transactions().count(), 9_999);
+        let t = store.transactions().find(|t| t.id == 1).unwrap();
+        assert_eq!(t.state(), DisputeState::Disputed);
+
+        // removed slots are reclaimed when the table is rebuilt
+        for id in 0..9_000 {
+            store.remove(id);
+        }
+        for id in 20_000..45_000 {
+            store.put(&Transaction::new(Type::Deposit, 5, id, amount));
+        }
+        assert_eq!(store.len(), 26_000);
+        assert_eq!(store.removed, 0);
+        assert_eq!(store.transactions().count(), 26_000);
+        assert_eq!(store.get(9_000).unwrap().client, 7);
+        assert_eq!(store.get(44_999).unwrap().client, 5);
+        assert!(store.get(8_999).is_none());
+        store.check().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the file follows the number of transactions, not their ids
+        let mut store = DiskTxStore::create(&path).unwrap();
+        for id in 0..100 {
+            store.put(&Transaction::new(Type::Deposit, 3, id * 42_949_672, amount));
+        }
+        store.put(&Transaction::new(Type::Deposit, 3, u32::MAX, amount));
+        assert_eq!(store.get(u32::MAX).unwrap().id, u32::MAX);
+        assert_eq!(store.len(), 101);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            MIN_DISK_SLOTS * RECORD_SIZE as u64
+        );
+        store.check().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_store_errors() {
+        let path = store_path("disk_store_errors");
+        let mut store = DiskTxStore::create(&path).unwrap();
+        let amount = "1.0".parse().unwrap();
+        store.put(&Transaction::new(Type::Deposit, 1, 1, amount));
+        store.check().unwrap();
+        // writes to a file opened read only fail
+        store.file = File::open(&path).unwrap();
+        store.put(&Transaction::new(Type::Deposit, 1, 2, amount));
+        assert!(matches!(store.check(), Err(Error::TxStore(_))));
+        // reported once
+        store.check().unwrap();
+        assert!(store.contains(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dense_tx_store() {
+        let mut store = DenseTxStore::new();
+        assert!(store.get(0).is_none());
+        let amount = "-0.0001".parse().unwrap();
+        // enough transactions to grow the table a few times
+        for id in 0..10_000 {
+            store.put(&Transaction::new(Type::Deposit, id as u16, id * 7, amount));
+        }
+        for id in 0..5_000 {
+            store.remove(id * 7);
+        }
+        // removed slots are reused
+        for id in 0..1_000 {
+            store.put(&Transaction::new(Type::Withdrawal, 1, id * 7, amount));
+        }
+        let t = Transaction::new(Type::Deposit, 3, 7 * 9_999, amount)
+            .with_asset("BTC".parse().unwrap())
+            .with_state(DisputeState::ChargedBack);
+        store.put(&t);
+        store.put(&Transaction::new(Type::Transfer, 3, 7 * 9_998, amount).with_recipient(4));
+        // a transfer's recipient does not outlive it
+        store.put(&Transaction::new(Type::Transfer, 3, 7 * 9_997, amount).with_recipient(4));
+        store.put(&Transaction::new(Type::Deposit, 3, 7 * 9_997, amount));
+        store.put(&Transaction::new(Type::Deposit, 3, 7 * 9_996, amount).with_fee(amount));
+
+        assert_eq!(store.len(), 6_000);
+        assert_eq!(store.transactions().count(), 6_000);
+        assert_eq!(store.get(7 * 9_998).unwrap().to, Some(4));
+        assert_eq!(store.get(7 * 9_997).unwrap().to, None);
+        assert_eq!(store.get(7 * 9_996).unwrap().fee(), amount);
+        assert!(store.get(7 * 9_997).unwrap().fee().is_zero());
+        assert!(!store.contains(7 * 4_999));
+        assert!(store.contains(7 * 5_000));
+        assert!(matches!(store.get(7).unwrap().r#type, Type::Withdrawal));
+        let updated = store.get(7 * 9_999).unwrap();
+        assert_eq!((updated.client, updated.amount), (3, amount));
+        assert_eq!(updated.state(), DisputeState::ChargedBack);
+        assert_eq!(updated.asset.code(), "BTC");
+        assert!(store.get(7).unwrap().asset.is_default());
+
+        // removing everything leaves no transaction behind
+        for id in 0..10_000 {
+            store.remove(id * 7);
+        }
+        assert!(store.is_empty());
+        assert!(store.transactions().next().is_none());
+        assert!(store.recipients.is_empty());
+        assert!(store.fees.is_empty());
+    }
+
+    /// Past 255 assets, those of new transactions are set aside
+    #[test]
+    fn dense_tx_store_assets() {
+        let mut store = DenseTxStore::new();
+        let asset = |id: u32| format!("A{}", id % 300).parse().unwrap();
+        let amount = Amount::from_units(1);
+        // enough transactions to rebuild the table on the way
+        for id in 0..3_000 {
+            store.put(&Transaction::new(Type::Deposit, 1, id, amount).with_asset(asset(id)));
+        }
+        assert_eq!(store.known_assets.len(), 255);
+        for id in 0..3_000 {
+            assert_eq!(store.get(id).unwrap().asset, asset(id));
+        }
+        // a transaction can move between the column and the side
+        store.put(&Transaction::new(Type::Deposit, 1, 299, amount));
+        assert!(store.get(299).unwrap().asset.is_default());
+        for id in 0..3_000 {
+            store.remove(id);
+        }
+        assert!(store.other_assets.is_empty());
+    }
+
+    #[test]
+    fn dense_wallets() {
+        let mut wallets = DenseWallets::new();
+        assert!(wallets.get(0).is_none());
+        assert!(wallets.get_mut(u16::MAX).is_none());
+        wallets.get_or_create_mut(u16::MAX);
+        wallets.get_or_create_mut(0);
+        wallets.get_or_create_mut(0);
+        let ids: Vec<_> = wallets.wallets().map(|c| c.id()).collect();
+        assert_eq!(ids, vec![0, u16::MAX]);
+        assert!(wallets.get(1).is_none());
+    }
+}
@@ -1,8 +1,13 @@
-use rand::random;
+use crate::amount::Amount;
+use crate::asset::Asset;
+use crate::storage::{DenseTxStore, TxStore};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use thiserror::Error;
 
-/// The type of the transaction (withdrawal, deposit, dispute, resolve, chargeback)
+/// The type of the transaction (withdrawal, deposit, transfer, dispute,
+/// resolve, chargeback)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(rename_all = "snake_case")]
@@ -12,80 +17,387 @@ pub enum Type {
     Dispute,
     Resolve,
     Chargeback,
+    /// Move funds from `client` to the recipient `to`, in one step
+    Transfer,
 }
 
-impl Type {
-    fn random() -> Self {
-        use Type::*;
-        match random::<u8>() % 100_u8 {
-            0..=29 => Withdrawal,
-            30..=59 => Deposit,
-            60..=69 => Dispute,
-            70..=89 => Resolve,
-            _ => Chargeback,
+/// Where a logged transaction stands in the dispute process
+///
+/// - `Processed` -> `Disputed` on a dispute
+/// - `Disputed` -> `Resolved` on a resolve
+/// - `Disputed` -> `ChargedBack` on a chargeback
+/// - `Resolved` -> `Disputed` on a dispute, if re-disputes are allowed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    /// The transaction went through, and was never disputed
+    #[default]
+    Processed,
+    /// The transaction is under dispute
+    Disputed,
+    /// The dispute was resolved, the transaction stands
+    Resolved,
+    /// The transaction was reversed. This is final.
+    ChargedBack,
+}
+
+/// The events that move a transaction through its dispute lifecycle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeEvent {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+/// Why a transaction cannot move to another dispute state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum InvalidTransition {
+    #[error("transaction is already under dispute")]
+    AlreadyDisputed,
+    #[error("transaction is not under dispute")]
+    NotDisputed,
+    #[error("transaction can no longer be disputed")]
+    Closed,
+}
+
+impl DisputeState {
+    /// Get the state following `event`.
+    ///
+    /// `allow_redispute` tells if a resolved transaction can be disputed
+    /// again. A charged back transaction never can.
+    pub fn next(
+        self,
+        event: DisputeEvent,
+        allow_redispute: bool,
+    ) -> std::result::Result<DisputeState, InvalidTransition> {
+        use DisputeEvent::*;
+        use DisputeState::*;
+        match (self, event) {
+            (Processed, Dispute) => Ok(Disputed),
+            (Resolved, Dispute) if allow_redispute => Ok(Disputed),
+            (Resolved, Dispute) | (ChargedBack, Dispute) => Err(InvalidTransition::Closed),
+            (Disputed, Dispute) => Err(InvalidTransition::AlreadyDisputed),
+            (Disputed, Resolve) => Ok(Resolved),
+            (Disputed, Chargeback) => Ok(ChargedBack),
+            (_, Resolve) | (_, Chargeback) => Err(InvalidTransition::NotDisputed),
         }
     }
 }
 
 /// A Transaction record
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Transaction {
     pub r#type: Type,
     pub client: u16,
     pub id: u32,
-    #[serde(default)]
-    pub amount: f64,
-    #[serde(skip_deserializing)]
-    under_dispute: bool,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_or_zero")]
+    pub amount: Amount,
+    /// Disputes act on the asset of the transaction they reference, and
+    /// ignore their own
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub asset: Asset,
+    /// The recipient of a transfer. Other types of transactions have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u16>,
+    #[serde(skip)]
+    state: DisputeState,
+    #[serde(skip)]
+    fee: Amount,
 }
 
-static mut ID: u32 = 1;
-
 impl Transaction {
-    /// Create a new transaction filled with random data.
-    pub fn new_random() -> Self {
-        let t = Transaction {
-            r#type: Type::random(),
-            client: rand::random(),
-            id: unsafe { ID.wrapping_sub(random()) },
-            amount: rand::random(),
-            under_dispute: rand::random(),
-        };
-        // Change the ID 30% of the time, to allow for generating
-        // more plausible scenarios
-        unsafe {
-            if (random::<u8>() % 100) < 30 {
-                ID += 1;
-            }
+    /// Create a new transaction, in the default asset
+    pub fn new(r#type: Type, client: u16, id: u32, amount: Amount) -> Self {
+        Transaction {
+            r#type,
+            client,
+            id,
+            amount,
+            asset: Asset::default(),
+            to: None,
+            state: DisputeState::Processed,
+            fee: Amount::ZERO,
         }
-        t
+    }
+
+    /// The same transaction, in asset `asset`
+    pub fn with_asset(mut self, asset: Asset) -> Self {
+        self.asset = asset;
+        self
+    }
+
+    /// The same transaction, sent to client `to`
+    pub fn with_recipient(mut self, to: u16) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    /// Where the transaction stands in the dispute process
+    pub fn state(&self) -> DisputeState {
+        self.state
+    }
+
+    /// The same transaction, moved to dispute state `state`
+    pub(crate) fn with_state(mut self, state: DisputeState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// The fee the client paid on the transaction, see `fee::Fees`
+    pub fn fee(&self) -> Amount {
+        self.fee
+    }
+
+    /// The same transaction, that paid `fee`
+    pub(crate) fn with_fee(mut self, fee: Amount) -> Self {
+        self.fee = fee;
+        self
     }
 
     /// Check if the transaction is under dispute
     pub fn under_dispute(&self) -> bool {
-        self.under_dispute
+        self.state == DisputeState::Disputed
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Withdrawal => "withdrawal",
+            Type::Deposit => "deposit",
+            Type::Dispute => "dispute",
+            Type::Resolve => "resolve",
+            Type::Chargeback => "chargeback",
+            Type::Transfer => "transfer",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Transaction {
+    /// Write the transaction the way it would appear in a CSV input
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.r#type, self.client, self.id)?;
+        match self.r#type {
+            Type::Deposit | Type::Withdrawal | Type::Transfer => write!(f, ",{}", self.amount)?,
+            _ if !self.asset.is_default() => f.write_str(",")?,
+            _ => (),
+        }
+        if !self.asset.is_default() || self.to.is_some() {
+            write!(f, ",{}", self.asset)?;
+        }
+        if let Some(to) = self.to {
+            write!(f, ",{}", to)?;
+        }
+        Ok(())
+    }
+}
+
+/// A dispute related event that happened to a logged transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeRecord {
+    pub event: DisputeEvent,
+    /// The client that issued the event
+    pub client: u16,
+    /// The state the transaction moved to
+    pub state: DisputeState,
+    /// The fee the client paid on the event
+    #[serde(default, skip_serializing_if = "Amount::is_zero")]
+    pub fee: Amount,
+}
+
+/// How long a logged transaction can be disputed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeWindow {
+    /// Transactions can be disputed forever
+    #[default]
+    Unbounded,
+    /// Only the `n` most recent deposits, withdrawals and transfers can be disputed
+    Transactions(usize),
+    /// A transaction can be disputed until `n` more transactions were
+    /// submitted to the engine after it
+    Ticks(u64),
+}
+
+/// A compact set of transaction ids, one bit per id.
+/// Ids are grouped in chunks of 64, so sparse ids do not cost a full bitmap.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct IdSet {
+    chunks: HashMap<u32, u64>,
+}
+
+impl IdSet {
+    pub(crate) fn insert(&mut self, id: u32) {
+        *self.chunks.entry(id >> 6).or_default() |= 1 << (id & 63);
+    }
+
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        self.chunks
+            .get(&(id >> 6))
+            .is_some_and(|chunk| chunk & (1 << (id & 63)) != 0)
+    }
+
+    pub(crate) fn remove(&mut self, id: u32) {
+        if let Some(chunk) = self.chunks.get_mut(&(id >> 6)) {
+            *chunk &= !(1 << (id & 63));
+            if *chunk == 0 {
+                self.chunks.remove(&(id >> 6));
+            }
+        }
     }
 }
 
-/// The TransactionLog holds the list of all valid transactions processed
-/// by the engine.
-/// Every transaction that gets successfully processed by the engine gets
+/// A transaction of the log, along with where it stands in the dispute
+/// process
+#[derive(Serialize, Deserialize, Debug)]
+struct LoggedTransaction {
+    r#type: Type,
+    client: u16,
+    id: u32,
+    amount: Amount,
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    asset: Asset,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<u16>,
+    #[serde(default, skip_serializing_if = "Amount::is_zero")]
+    fee: Amount,
+    state: DisputeState,
+}
+
+/// Everything a `TransactionLog` knows, to save it and load it back exactly
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogState {
+    window: DisputeWindow,
+    now: u64,
+    /// sorted by id
+    transactions: Vec<LoggedTransaction>,
+    /// sorted by transaction id
+    history: Vec<(u32, Vec<DisputeRecord>)>,
+    order: VecDeque<(u32, u64)>,
+    expired: IdSet,
+}
+
+impl LogState {
+    /// The dispute window of the saved log
+    pub fn window(&self) -> DisputeWindow {
+        self.window
+    }
+}
+
+/// The TransactionLog holds the list of all valid deposits, withdrawals and
+/// transfers processed by the engine.
+/// Every one of them that gets successfully processed by the engine gets
 /// `push()`ed to the log.
 /// It can then be queried to `find()` a specific transaction by id.
 /// Attempting to add a transaction with an id that already was recorded
 /// silently fails.
-#[derive(Debug)]
-pub struct TransactionLog {
-    /// map of transaction id to transaction
-    transactions: HashMap<u32, Transaction>,
+///
+/// Disputes, resolves and chargebacks do not move money on their own, and
+/// refer to an existing transaction. They are kept apart, in the `history()`
+/// of the transaction they target.
+///
+/// To bound its memory, the log can be given a `DisputeWindow`. Transactions
+/// that age out of the window can no longer be disputed, and are evicted. Only
+/// their id is kept, so that it cannot be used again. A transaction that is
+/// under dispute when it ages out stays until its dispute is closed.
+///
+/// Transactions are kept in a `TxStore`, a `DenseTxStore` unless the log is
+/// created `with_store()`.
+#[derive(Debug, Default)]
+pub struct TransactionLog<S: TxStore = DenseTxStore> {
+    /// store of the logged transactions, by id
+    transactions: S,
+    /// map of transaction id to the dispute events it went through
+    history: HashMap<u32, Vec<DisputeRecord>>,
+    window: DisputeWindow,
+    /// logical clock, advanced once per transaction submitted to the engine
+    now: u64,
+    /// ids of the logged transactions, with the time they were logged, oldest first
+    order: VecDeque<(u32, u64)>,
+    /// ids of the transactions that aged out of the dispute window
+    expired: IdSet,
 }
 
 impl TransactionLog {
     /// Create a new empty transaction log
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new empty transaction log, that only keeps the transactions
+    /// within `window`
+    pub fn with_window(window: DisputeWindow) -> Self {
+        Self::with_store(DenseTxStore::new(), window)
+    }
+}
+
+impl<S: TxStore> TransactionLog<S> {
+    /// Create a new transaction log, that keeps the transactions within
+    /// `window` in `store`
+    pub fn with_store(store: S, window: DisputeWindow) -> Self {
+        TransactionLog {
+            transactions: store,
+            history: HashMap::new(),
+            window,
+            now: 0,
+            order: VecDeque::new(),
+            expired: IdSet::default(),
+        }
+    }
+
+    /// Load a log saved with `save_state()`, keeping its transactions in
+    /// `store`. The log keeps the dispute window it was saved with.
+    pub fn from_state(mut store: S, state: LogState) -> Self {
+        for t in state.transactions {
+            let mut logged = Transaction::new(t.r#type, t.client, t.id, t.amount);
+            logged.to = t.to;
+            let logged = logged.with_asset(t.asset).with_fee(t.fee);
+            store.put(&logged.with_state(t.state));
+        }
         TransactionLog {
-            transactions: HashMap::new(),
+            transactions: store,
+            history: state.history.into_iter().collect(),
+            window: state.window,
+            now: state.now,
+            order: state.order,
+            expired: state.expired,
+        }
+    }
+
+    /// Everything the log knows, to load it back later with `from_state()`
+    pub fn save_state(&self) -> LogState {
+        let mut transactions: Vec<_> = self
+            .transactions
+            .transactions()
+            .map(|t| LoggedTransaction {
+                state: t.state(),
+                fee: t.fee(),
+                r#type: t.r#type,
+                client: t.client,
+                id: t.id,
+                amount: t.amount,
+                asset: t.asset,
+                to: t.to,
+            })
+            .collect();
+        transactions.sort_by_key(|t| t.id);
+        let mut history: Vec<_> = self
+            .history
+            .iter()
+            .map(|(id, records)| (*id, records.clone()))
+            .collect();
+        history.sort_by_key(|(id, _)| *id);
+        LogState {
+            window: self.window,
+            now: self.now,
+            transactions,
+            history,
+            order: self.order.clone(),
+            expired: self.expired.clone(),
         }
     }
 
@@ -94,51 +406,317 @@ impl TransactionLog {
         self.transactions.len()
     }
 
-    /// Add a new transaction to the list
+    /// Checks if the log holds no transaction
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Report the first error the store of the log ran into since the last
+    /// check, see `TxStore::check()`
+    pub fn check_store(&self) -> crate::error::Result<()> {
+        self.transactions.check()
+    }
+
+    /// Advance the logical clock of the log by one tick, evicting the
+    /// transactions that age out of the dispute window
+    pub fn tick(&mut self) {
+        self.advance_to(self.now + 1);
+    }
+
+    /// Advance the logical clock of the log to `now`, evicting the
+    /// transactions that age out of the dispute window
+    pub fn advance_to(&mut self, now: u64) {
+        self.now = self.now.max(now);
+        self.evict();
+    }
+
+    /// The logical clock of the log: how many transactions were submitted to
+    /// the engine so far
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Add a new deposit, withdrawal or transfer to the list.
+    /// Other types of transactions are ignored, see `record()`.
     pub fn push(&mut self, t: &Transaction) {
-        match self.transactions.get(&t.id) {
-            Some(_) => (), // silently fail
-            None => {
-                self.transactions.insert(t.id, t.clone());
-            }
-        };
+        if !matches!(t.r#type, Type::Deposit | Type::Withdrawal | Type::Transfer)
+            || self.contains(t.id)
+        {
+            return; // silently fail
+        }
+        self.transactions.put(t);
+        if self.window != DisputeWindow::Unbounded {
+            self.order.push_back((t.id, self.now));
+            self.evict();
+        }
     }
 
     /// Find a transaction with a given id in the log
-    pub fn find(&self, tx_id: u32) -> Option<&Transaction> {
-        self.transactions.get(&tx_id)
+    pub fn find(&self, tx_id: u32) -> Option<Transaction> {
+        self.transactions.get(tx_id)
     }
 
-    /// Checks if the transaction with id `tx_id` exits in the log
+    /// Checks if the transaction with id `tx_id` exits in the log, or did
+    /// before it aged out of the dispute window
     pub fn contains(&self, tx_id: u32) -> bool {
-        self.transactions.contains_key(&tx_id)
+        self.transactions.contains(tx_id) || self.expired.contains(tx_id)
+    }
+
+    /// Checks if the transaction with id `tx_id` aged out of the dispute
+    /// window
+    pub fn is_expired(&self, tx_id: u32) -> bool {
+        self.expired.contains(tx_id)
     }
 
-    /// Mark a transaction as under dispute
-    pub fn dispute(&mut self, tx_id: u32) {
-        match self.transactions.get_mut(&tx_id) {
-            None => (),
-            Some(t) => t.under_dispute = true,
+    /// Record that `event`, issued by `client`, moved transaction `t` to
+    /// another dispute `state`, and cost the client `fee`.
+    ///
+    /// `t` is the transaction as found in the log, see `find()`.
+    pub fn record(
+        &mut self,
+        t: Transaction,
+        client: u16,
+        event: DisputeEvent,
+        state: DisputeState,
+        fee: Amount,
+    ) {
+        let tx_id = t.id;
+        self.transactions.put(&t.with_state(state));
+        self.history.entry(tx_id).or_default().push(DisputeRecord {
+            event,
+            client,
+            state,
+            fee,
+        });
+        // a dispute that outlived the window is now closed
+        if state != DisputeState::Disputed && self.expired.contains(tx_id) {
+            self.transactions.remove(tx_id);
+            self.history.remove(&tx_id);
         }
     }
 
-    /// Reset the dispute status of a transaction
-    pub fn undispute(&mut self, tx_id: u32) {
-        match self.transactions.get_mut(&tx_id) {
-            None => (),
-            Some(t) => t.under_dispute = false,
+    /// The dispute events transaction `tx_id` went through, oldest first
+    pub fn history(&self, tx_id: u32) -> &[DisputeRecord] {
+        self.history.get(&tx_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Evict the transactions that are out of the dispute window
+    fn evict(&mut self) {
+        while let Some(&(id, logged_at)) = self.order.front() {
+            let expired = match self.window {
+                DisputeWindow::Unbounded => false,
+                DisputeWindow::Transactions(n) => self.order.len() > n,
+                DisputeWindow::Ticks(n) => self.now - logged_at > n,
+            };
+            if !expired {
+                break;
+            }
+            self.order.pop_front();
+            self.expired.insert(id);
+            if !self.find(id).is_some_and(|t| t.under_dispute()) {
+                self.transactions.remove(id);
+                self.history.remove(&id);
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::DisputeEvent::*;
+    use super::DisputeState::*;
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        assert_eq!(Processed.next(Dispute, false), Ok(Disputed));
+        assert_eq!(Disputed.next(Resolve, false), Ok(Resolved));
+        assert_eq!(Disputed.next(Chargeback, false), Ok(ChargedBack));
+        assert_eq!(Resolved.next(Dispute, true), Ok(Disputed));
+    }
+
+    #[test]
+    fn log_keeps_money_movements_only() {
+        let mut log = TransactionLog::new();
+        log.push(&Transaction::new(
+            Type::Deposit,
+            1,
+            1,
+            Amount::from_units(5),
+        ));
+        log.push(&Transaction::new(Type::Dispute, 1, 2, Amount::ZERO));
+        assert_eq!(log.len(), 1);
+        assert!(!log.contains(2));
+
+        let t = log.find(1).unwrap();
+        log.record(t.clone(), 1, Dispute, Disputed, Amount::ZERO);
+        log.record(t, 1, Resolve, Resolved, Amount::ZERO);
+        assert_eq!(log.find(1).unwrap().state(), Resolved);
+        let history: Vec<_> = log.history(1).iter().map(|r| r.event).collect();
+        assert_eq!(history, vec![Dispute, Resolve]);
+        assert!(log.history(2).is_empty());
+    }
+
+    #[test]
+    fn window_by_transactions() {
+        let mut log = TransactionLog::with_window(DisputeWindow::Transactions(2));
+        for id in 1..=3 {
+            log.push(&Transaction::new(Type::Deposit, 1, id, Amount::ZERO));
+        }
+        assert_eq!(log.len(), 2);
+        assert!(log.find(1).is_none());
+        assert!(log.is_expired(1));
+        // the id of an evicted transaction is still known
+        assert!(log.contains(1));
+        assert!(log.find(3).is_some());
+    }
+
+    #[test]
+    fn window_by_ticks() {
+        let mut log = TransactionLog::with_window(DisputeWindow::Ticks(2));
+        log.push(&Transaction::new(Type::Deposit, 1, 1, Amount::ZERO));
+        log.push(&Transaction::new(Type::Deposit, 1, 2, Amount::ZERO));
+        let t = log.find(2).unwrap();
+        log.record(t.clone(), 1, Dispute, Disputed, Amount::ZERO);
+        log.tick();
+        log.tick();
+        assert_eq!(log.len(), 2);
+        log.tick();
+        // transaction 2 is under dispute, it stays until the dispute is over
+        assert!(log.find(1).is_none());
+        assert!(log.is_expired(2));
+        assert!(log.find(2).is_some());
+        log.record(t, 1, Resolve, Resolved, Amount::ZERO);
+        assert!(log.find(2).is_none());
+        assert!(log.contains(2));
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        use InvalidTransition::*;
+        assert_eq!(Disputed.next(Dispute, true), Err(AlreadyDisputed));
+        assert_eq!(Processed.next(Resolve, true), Err(NotDisputed));
+        assert_eq!(Resolved.next(Chargeback, true), Err(NotDisputed));
+        assert_eq!(Resolved.next(Dispute, false), Err(Closed));
+        assert_eq!(ChargedBack.next(Dispute, true), Err(Closed));
+        assert_eq!(ChargedBack.next(Resolve, true), Err(NotDisputed));
+    }
+}
+
 pub mod utils {
-    use super::Transaction;
+    use super::{DisputeState, Transaction, Type};
+    use crate::amount::Amount;
+    use crate::asset::Asset;
+    use crate::error::{Error, Result};
+    use rand::distributions::{Distribution, WeightedIndex};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::VecDeque;
+    use std::ops::RangeInclusive;
+
+    /// Describes the shape of the generated transactions
+    #[derive(Debug, Clone)]
+    pub struct GeneratorConfig {
+        /// Relative weights of withdrawals, deposits, disputes, resolves and
+        /// chargebacks, in that order
+        pub type_weights: [u32; 5],
+        /// Range of the client ids
+        pub clients: RangeInclusive<u16>,
+        /// Range of the deposited and withdrawn amounts
+        pub amounts: RangeInclusive<Amount>,
+        /// Probability for a dispute, resolve or chargeback to reference a
+        /// recent deposit or withdrawal of the same client, rather than a
+        /// random id
+        pub dispute_probability: f64,
+        /// Probability to move on to a new transaction id after a transaction
+        pub new_id_probability: f64,
+        /// How far behind the latest id a transaction's id can be drawn
+        pub id_spread: u32,
+        /// Assets transactions are drawn in. With less than two assets, every
+        /// transaction is in the first one, or in the default asset.
+        pub assets: Vec<Asset>,
+    }
 
-    pub struct RandomTransactions {}
+    impl Default for GeneratorConfig {
+        fn default() -> Self {
+            GeneratorConfig {
+                type_weights: [30, 30, 10, 20, 10],
+                clients: 0..=u16::MAX,
+                amounts: Amount::from_units(1)..=Amount::from_units(u16::MAX as i64),
+                dispute_probability: 0.5,
+                // Change the ID 30% of the time, to allow for generating
+                // more plausible scenarios
+                new_id_probability: 0.3,
+                id_spread: u32::MAX,
+                assets: Vec::new(),
+            }
+        }
+    }
+
+    /// Number of recent deposits and withdrawals disputes are drawn from
+    const RECENT: usize = 1024;
+
+    /// An endless stream of random transactions.
+    ///
+    /// Two generators built with the same seed and configuration yield the
+    /// exact same transactions.
+    pub struct RandomTransactions {
+        rng: StdRng,
+        config: GeneratorConfig,
+        types: WeightedIndex<u32>,
+        next_id: u32,
+        /// client and id of the latest deposits and withdrawals
+        recent: VecDeque<(u16, u32)>,
+    }
 
     impl RandomTransactions {
+        /// Generate transactions with the default configuration, from a
+        /// random seed
         pub fn new() -> Self {
-            RandomTransactions {}
+            Self::with_seed(rand::random())
+        }
+
+        /// Generate transactions with the default configuration
+        pub fn with_seed(seed: u64) -> Self {
+            Self::with_config(GeneratorConfig::default(), seed)
+                .expect("the default configuration is valid")
+        }
+
+        /// Generate transactions shaped by `config`.
+        ///
+        /// Fails if a range of `config` is empty, if all the type weights are
+        /// 0, or if a probability is not within `0.0..=1.0`.
+        pub fn with_config(config: GeneratorConfig, seed: u64) -> Result<Self> {
+            let invalid = |what| Err(Error::InvalidGenerator(what));
+            if config.clients.is_empty() {
+                return invalid("empty range of clients");
+            }
+            if config.amounts.is_empty() {
+                return invalid("empty range of amounts");
+            }
+            if !(0.0..=1.0).contains(&config.dispute_probability) {
+                return invalid("dispute probability out of 0..=1");
+            }
+            if !(0.0..=1.0).contains(&config.new_id_probability) {
+                return invalid("new id probability out of 0..=1");
+            }
+            let types = match WeightedIndex::new(config.type_weights) {
+                Ok(types) => types,
+                Err(_) => return invalid("all type weights are 0"),
+            };
+            Ok(RandomTransactions {
+                rng: StdRng::seed_from_u64(seed),
+                types,
+                config,
+                next_id: 1,
+                recent: VecDeque::with_capacity(RECENT),
+            })
+        }
+    }
+
+    impl Default for RandomTransactions {
+        fn default() -> Self {
+            Self::new()
         }
     }
 
@@ -146,8 +724,145 @@ pub mod utils {
         type Item = Transaction;
 
         fn next(&mut self) -> Option<Self::Item> {
-            let tx = Transaction::new_random();
+            use Type::*;
+            let rng = &mut self.rng;
+            let r#type =
+                [Withdrawal, Deposit, Dispute, Resolve, Chargeback][self.types.sample(rng)].clone();
+            let amounts = &self.config.amounts;
+            let mut tx = Transaction {
+                r#type,
+                client: rng.gen_range(self.config.clients.clone()),
+                id: self
+                    .next_id
+                    .wrapping_sub(rng.gen_range(0..=self.config.id_spread)),
+                amount: Amount::from_units(
+                    rng.gen_range(amounts.start().units()..=amounts.end().units()),
+                ),
+                asset: Asset::default(),
+                to: None,
+                fee: Amount::ZERO,
+                state: DisputeState::Processed,
+            };
+            match tx.r#type {
+                Deposit | Withdrawal => {
+                    if self.recent.len() == RECENT {
+                        self.recent.pop_front();
+                    }
+                    self.recent.push_back((tx.client, tx.id));
+                }
+                _ => {
+                    if !self.recent.is_empty() && rng.gen_bool(self.config.dispute_probability) {
+                        (tx.client, tx.id) = self.recent[rng.gen_range(0..self.recent.len())];
+                    }
+                }
+            }
+            // only draw an asset when there is a choice, so that a seed yields
+            // the same stream as before there were assets
+            let tx = match self.config.assets.as_slice() {
+                [] => tx,
+                [asset] => tx.with_asset(*asset),
+                assets => tx.with_asset(assets[rng.gen_range(0..assets.len())]),
+            };
+            if rng.gen_bool(self.config.new_id_probability) {
+                self.next_id = self.next_id.wrapping_add(1);
+            }
             Some(tx)
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn replayable() {
+            let a: Vec<String> = RandomTransactions::with_seed(7)
+                .take(100)
+                .map(|t| t.to_string())
+                .collect();
+            let b: Vec<String> = RandomTransactions::with_seed(7)
+                .take(100)
+                .map(|t| t.to_string())
+                .collect();
+            assert_eq!(a, b);
+        }
+
+        #[test]
+        fn configured() {
+            let config = GeneratorConfig {
+                type_weights: [0, 1, 0, 0, 0],
+                clients: 3..=4,
+                amounts: Amount::from_units(10)..=Amount::from_units(20),
+                dispute_probability: 0.0,
+                new_id_probability: 1.0,
+                id_spread: 0,
+                assets: Vec::new(),
+            };
+            for (i, t) in RandomTransactions::with_config(config, 1)
+                .unwrap()
+                .take(1000)
+                .enumerate()
+            {
+                assert!(matches!(t.r#type, Type::Deposit));
+                assert!((3..=4).contains(&t.client));
+                assert!((10..=20).contains(&t.amount.units()));
+                assert!(!t.under_dispute());
+                assert_eq!(t.id, i as u32 + 1);
+            }
+        }
+
+        #[test]
+        fn invalid_config() {
+            #[allow(clippy::reversed_empty_ranges)]
+            let configs = vec![
+                GeneratorConfig {
+                    clients: 4..=3,
+                    ..GeneratorConfig::default()
+                },
+                GeneratorConfig {
+                    amounts: Amount::from_units(2)..=Amount::from_units(1),
+                    ..GeneratorConfig::default()
+                },
+                GeneratorConfig {
+                    dispute_probability: 1.5,
+                    ..GeneratorConfig::default()
+                },
+                GeneratorConfig {
+                    new_id_probability: -0.1,
+                    ..GeneratorConfig::default()
+                },
+                GeneratorConfig {
+                    type_weights: [0; 5],
+                    ..GeneratorConfig::default()
+                },
+            ];
+            for config in configs {
+                assert!(matches!(
+                    RandomTransactions::with_config(config, 1),
+                    Err(Error::InvalidGenerator(_))
+                ));
+            }
+        }
+
+        #[test]
+        fn disputes_reference_earlier_transactions() {
+            let config = GeneratorConfig {
+                dispute_probability: 1.0,
+                ..GeneratorConfig::default()
+            };
+            let mut issued = std::collections::HashSet::new();
+            for t in RandomTransactions::with_config(config, 3)
+                .unwrap()
+                .take(10_000)
+            {
+                match t.r#type {
+                    Type::Deposit | Type::Withdrawal => {
+                        issued.insert((t.client, t.id));
+                    }
+                    _ if issued.is_empty() => (),
+                    _ => assert!(issued.contains(&(t.client, t.id)), "{}", t),
+                }
+            }
+        }
+    }
 }
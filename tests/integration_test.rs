@@ -1,23 +1,106 @@
-use pay_engine::{engine, transaction::utils::RandomTransactions};
+use pay_engine::client::{Client, ClientWallets};
+use pay_engine::storage::DiskTxStore;
+use pay_engine::transaction::{DisputeWindow, TransactionLog};
+use pay_engine::{engine, output, parser::Parser, transaction::utils::RandomTransactions};
+use std::collections::HashMap;
 use std::iter::Iterator;
 
+/// Seed of the generated transactions, so every run replays the same stream
+const SEED: u64 = 42;
+
 #[test]
 #[ignore]
 fn exec_100_million_tx() {
-    let tx_gen = RandomTransactions::new();
-    engine::run(
-        &mut tx_gen
-            .into_iter()
-            .take(/*u32::max_value()*/ 100_000_000 as usize),
-    );
+    let tx_gen = RandomTransactions::with_seed(SEED);
+    engine::run(&mut tx_gen.into_iter().take(/*u32::max_value()*/ 100_000_000));
 }
 
 #[test]
 fn exec_1_million_tx() {
-    let tx_gen = RandomTransactions::new();
-    engine::run(
-        &mut tx_gen
-            .into_iter()
-            .take(/*u32::max_value()*/ 1_000_000 as usize),
+    let tx_gen = RandomTransactions::with_seed(SEED);
+    engine::run(&mut tx_gen.into_iter().take(/*u32::max_value()*/ 1_000_000));
+}
+
+/// Run every `inputs/*.csv` scenario, and compare the balances with the
+/// matching `_expected` file.
+#[test]
+fn input_scenarios() {
+    let mut inputs: Vec<_> = std::fs::read_dir("inputs")
+        .expect("inputs directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "csv"))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty());
+
+    for input in inputs {
+        let path = input.to_str().unwrap();
+        let expected = std::fs::read_to_string(format!("{}_expected", path)).unwrap();
+        let mut parser = Parser::new(path).unwrap();
+        let (wallets, _) = engine::run(&mut parser.by_ref().filter_map(Result::ok));
+
+        let mut out = Vec::new();
+        output::write_balances(&wallets, output::Format::Csv, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected, "{}", path);
+    }
+}
+
+/// The on-disk transaction store must not change what the engine does
+#[test]
+fn disk_tx_store() {
+    let path = std::env::temp_dir().join(format!("pay-engine-tx-store-{}", std::process::id()));
+    let store = DiskTxStore::create(&path).unwrap();
+    let mut on_disk = (
+        ClientWallets::new(),
+        TransactionLog::with_store(store, DisputeWindow::Unbounded),
+    );
+    let mut in_memory = (ClientWallets::new(), TransactionLog::new());
+    let config = engine::Config::default();
+
+    for t in RandomTransactions::with_seed(SEED).take(100_000) {
+        let expected = engine::process(&config, &t, &mut in_memory.0, &mut in_memory.1);
+        let outcome = engine::process(&config, &t, &mut on_disk.0, &mut on_disk.1);
+        assert_eq!(outcome, expected);
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    let balances = |wallets: &ClientWallets| {
+        let mut out = Vec::new();
+        output::write_balances(wallets, output::Format::Csv, &mut out).unwrap();
+        out
+    };
+    assert_eq!(balances(&on_disk.0), balances(&in_memory.0));
+    assert_eq!(on_disk.1.len(), in_memory.1.len());
+}
+
+/// The dense stores hold the same wallets and transactions as `HashMap`s
+#[test]
+fn dense_storage() {
+    let config = engine::Config {
+        dispute_window: DisputeWindow::Ticks(10_000),
+        ..engine::Config::default()
+    };
+    let mut dense = (
+        ClientWallets::new(),
+        TransactionLog::with_window(config.dispute_window),
+    );
+    let mut hash_maps = (
+        ClientWallets::with_store(HashMap::new()),
+        TransactionLog::with_store(HashMap::new(), config.dispute_window),
+    );
+
+    for t in RandomTransactions::with_seed(SEED).take(200_000) {
+        let expected = engine::process(&config, &t, &mut hash_maps.0, &mut hash_maps.1);
+        let outcome = engine::process(&config, &t, &mut dense.0, &mut dense.1);
+        assert_eq!(outcome, expected);
+    }
+
+    let clients = |clients: Vec<&Client>| serde_json::to_string(&clients).unwrap();
+    assert_eq!(clients(dense.0.clients()), clients(hash_maps.0.clients()));
+    // compared as values, sets of expired ids are serialized in no
+    // particular order
+    assert_eq!(
+        serde_json::to_value(dense.1.save_state()).unwrap(),
+        serde_json::to_value(hash_maps.1.save_state()).unwrap()
     );
 }